@@ -18,30 +18,143 @@ pub struct Opts {
     #[clap(short, long)]
     pub examples: bool,
 
-    /// Print the updates to the prompt as they happen.  This will at most print 3 lines of text
+    /// Print the updates to the prompt as they happen.  This will at most print 4 lines of text
     /// which is useful for asynchronous updating when using in ZSH with zle -F or similar.
+    /// The branch and the local status are computed at the same time and printed in the order
+    /// they finish, with the cached local status, if any, ahead of the scanned one.
     #[clap(short, long)]
     pub print_updates: bool,
 
-    /// default_branch to use when printing diff status
-    #[clap(
-        short,
-        long,
-        default_value = "master",
-        env = "GIT_PROMPT_DEFAULT_BRANCH"
-    )]
-    pub default_branch: String,
+    /// output format, either 'text' for the prompt or 'json' for other tools.
+    /// With --print-updates every update is a JSON object on its own line.
+    #[clap(long, default_value = "text", possible_values = &["text", "json"])]
+    pub format: String,
+
+    /// default_branch to use when printing diff status for branches without
+    /// an upstream. When not given, the branch the remote HEAD points to is
+    /// used, then init.defaultBranch and then the first existing candidate.
+    #[clap(short, long, env = "GIT_PROMPT_DEFAULT_BRANCH")]
+    pub default_branch: Option<String>,
+
+    /// comma separated default branch candidates to try when the default branch
+    /// cannot be detected. [default: main,master,trunk,develop]
+    #[clap(long)]
+    pub default_branch_candidates: Option<String>,
 
     /// status symbols to be used for the output. The format is
-    /// 'ok|staged|unmerged|unstaged|untracked'.
-    #[clap(long, default_value = "✔|●|✖|✚|…")]
-    pub status_symbols: String,
+    /// 'ok|staged|unmerged|unstaged|untracked|stashed|unknown|new|modified|deleted|renamed|typechange',
+    /// where unknown marks the segments which timed out and the kinds of the
+    /// changes are shown by 'local_detailed'. [default: ✔|●|✖|✚|…|⚑|?|A|M|D|R|T]
+    #[clap(long)]
+    pub status_symbols: Option<String>,
+
+    /// branch symbols to be used for the output. The format is
+    /// 'ahead|behind|default_ahead|default_behind|detached|in_sync|no_upstream|gone|push_ahead|stale',
+    /// where the default ones are used for the comparison with the default
+    /// branch, detached marks a detached HEAD, in_sync, no_upstream and gone
+    /// are shown instead of the counts when the branch is in sync with its
+    /// upstream, does not have one or its upstream was deleted and push_ahead
+    /// counts the commits not pushed when the branch is pushed to another
+    /// remote than it is pulled from and stale marks an old fetch.
+    /// [default: ↑|↓|⇡|⇣|➦|=||⊘|⇈|↻]
+    #[clap(long)]
+    pub branch_symbols: Option<String>,
+
+    /// colorscheme to be used. Either a preset or comma-separated byte values.
+    /// [default: simple]
+    #[clap(long)]
+    pub colorscheme: Option<String>,
+
+    /// shell to escape the color codes for, one of 'zsh', 'bash', 'fish', 'tmux'
+    /// or 'none'. [default: none]
+    #[clap(long, env = "GIT_PROMPT_SHELL")]
+    pub shell: Option<String>,
+
+    /// layout of the prompt. Fields are 'state', 'branch', 'upstream',
+    /// 'ahead_behind', 'default_ahead_behind', 'default_branch', 'fetch', 'local',
+    /// 'local_detailed' and 'onto', '{field}'
+    /// prints a field and '{?field:text}' prints the text only if the field is
+    /// not empty.
+    /// Use '{{' and '}}' for literal braces.
+    #[clap(long)]
+    pub layout: Option<String>,
+
+    /// milliseconds to wait for the branch and the local status, the segments
+    /// which are not ready by then are shown as unknown. 0 waits forever.
+    /// [default: 0]
+    #[clap(long)]
+    pub timeout: Option<u64>,
+
+    /// number of commits to count ahead of and behind the upstream and the
+    /// default branch, more are shown as e.g. '↑999+'. Once one of the counts is
+    /// over it, the other one is only what was found by then, e.g. '↑3+' or '↑?'
+    /// for none. 0 counts all of them.
+    /// [default: 0]
+    #[clap(long)]
+    pub ahead_behind_limit: Option<u64>,
+
+    /// minutes after which the time since the last fetch is shown, e.g. '↻3d',
+    /// as the ahead/behind counts might be out of date by then. 0 never shows
+    /// it. [default: 0]
+    #[clap(long)]
+    pub stale_fetch: Option<u64>,
 
-    /// branch symbols to be used for the output. The format is 'ahead|behind'.
-    #[clap(long, default_value = "↑|↓")]
-    pub branch_symbols: String,
+    /// minutes between the runs of git fetch in the background, which only
+    /// start when the remotes were not fetched for that long. The prompt does
+    /// not wait for them. 0 never fetches. [default: 0]
+    #[clap(long)]
+    pub auto_fetch: Option<u64>,
 
-    /// default_branch to use when printing diff status
-    #[clap(long, default_value = "simple")]
-    pub colorscheme: String,
+    /// untracked files to count, 'none' skips looking for them, 'normal' counts
+    /// the untracked directories once and 'all' counts the files in them.
+    /// [default: status.showUntrackedFiles or normal]
+    #[clap(long, possible_values = &["none", "normal", "all"])]
+    pub untracked: Option<String>,
+
+    /// comma separated steps to describe a detached HEAD with, tried in order:
+    /// 'tag' for a tag pointing at it, 'nearest' for the nearest tag, e.g.
+    /// 'v1.2.0~3', and 'remote' for a remote branch containing it, which has to
+    /// look at every remote branch and is therefore not tried by default. 'none'
+    /// shows the abbreviated commit id. [default: tag,nearest]
+    #[clap(long)]
+    pub describe: Option<String>,
+
+    /// Do not print the cached local status of the previous prompt while the
+    /// worktree is scanned again, and scan the whole worktree instead of asking
+    /// the core.fsmonitor hook what changed or using the untracked cache
+    #[clap(long)]
+    pub no_cache: bool,
+
+    // scans the worktree for the next prompt in the background
+    #[clap(long, hidden = true)]
+    pub refresh_cache: bool,
+
+    /// config file to read the settings from instead of
+    /// $XDG_CONFIG_HOME/git-prompt/config.toml. The flags take precedence over
+    /// the config file.
+    #[clap(long, env = "GIT_PROMPT_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Clap)]
+pub enum Command {
+    /// Keep the repositories open and serve their status over a Unix socket,
+    /// the local status is cached until something changes in the worktree.
+    Daemon {
+        /// socket to listen on instead of $XDG_RUNTIME_DIR/git-prompt.sock
+        #[clap(long, env = "GIT_PROMPT_SOCKET")]
+        socket: Option<PathBuf>,
+    },
+    /// Ask the daemon for the prompt, the options are the same as without a
+    /// subcommand.  Falls back to computing the prompt itself when the daemon
+    /// is not running.
+    Query {
+        /// socket the daemon listens on instead of
+        /// $XDG_RUNTIME_DIR/git-prompt.sock
+        #[clap(long, env = "GIT_PROMPT_SOCKET")]
+        socket: Option<PathBuf>,
+    },
 }
@@ -0,0 +1,213 @@
+//! Fetching the remotes in the background, so that the ahead/behind counts are
+//! not only as fresh as the last fetch by hand.  The time of the last fetch is
+//! kept in a file next to the cached status, and only the prompt which manages
+//! to create the lock file beside it may start the next one, so that the
+//! prompts of several shells do not fetch at the same time.
+use super::cache;
+use super::model::{Repo, R};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const STAMP: &str = "git-prompt-fetch";
+
+// a lock older than this was left behind by a prompt which was killed
+const STALE_LOCK: Duration = Duration::from_secs(60);
+
+/// Start git fetch in the background when the remotes were not fetched for
+/// the interval, and tell whether it was started.
+pub fn start(repo: &git2::Repository, interval: Duration) -> R<bool> {
+    let remotes = repo.remotes().map_err(|e| format!("{:?}", e))?;
+    if remotes.is_empty() || !claim(repo, interval, SystemTime::now()) {
+        return Ok(false);
+    }
+    let mut child = command(repo)
+        .spawn()
+        .map_err(|e| format!("git fetch: {}", e))?;
+    // the daemon keeps running, so the process has to be reaped
+    thread::spawn(move || child.wait());
+    Ok(true)
+}
+
+// which only fetches the remote of the current branch, or origin, like git
+// fetch without arguments does
+fn command(repo: &git2::Repository) -> Command {
+    let mut c = Command::new("git");
+    // the gc or maintenance started after the fetch would take the locks of
+    // the repository in the background
+    c.args(["fetch", "--quiet", "--no-auto-gc"])
+        .current_dir(repo.workdir().unwrap_or_else(|| repo.path()))
+        // it would wait for a password forever
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    let config = repo.config().and_then(|c| c.get_string("core.sshCommand"));
+    let ssh = env::var("GIT_SSH_COMMAND").ok().or_else(|| config.ok());
+    if let Some(ssh) = batch_ssh(ssh, env::var_os("GIT_SSH").is_some()) {
+        c.env("GIT_SSH_COMMAND", ssh);
+    }
+    c
+}
+
+// ssh asks for passphrases and host keys on /dev/tty, which neither
+// GIT_TERMINAL_PROMPT nor the closed stdin prevent, unlike its batch mode.
+// The command from GIT_SSH_COMMAND or core.sshCommand gets the option too,
+// but a program in GIT_SSH does not take options and is left alone.
+fn batch_ssh(command: Option<String>, program: bool) -> Option<String> {
+    match command {
+        Some(c) => Some(format!("{} -o BatchMode=yes", c)),
+        None if program => None,
+        None => Some("ssh -o BatchMode=yes".into()),
+    }
+}
+
+/// Whether the interval has passed since the last fetch, either by hand or in
+/// the background, in which case the time of the next one is stored.
+fn claim(repo: &git2::Repository, interval: Duration, now: SystemTime) -> bool {
+    for stamp in cache::locations(repo, STAMP) {
+        let lock = stamp.with_extension("lock");
+        match acquire(&lock, now) {
+            Ok(true) => {}
+            // another prompt is about to fetch
+            Ok(false) => return false,
+            // the location is not writable, e.g. a read-only git directory
+            Err(_) => continue,
+        }
+        let last = read(&stamp).into_iter().chain(repo.last_fetch()).max();
+        let due = match last {
+            Some(t) => older(t, now, interval),
+            None => true,
+        };
+        let claimed = due && write(&stamp, now).is_ok();
+        let _ = fs::remove_file(&lock);
+        return claimed;
+    }
+    false
+}
+
+fn acquire(lock: &Path, now: SystemTime) -> io::Result<bool> {
+    if let Some(dir) = lock.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    match OpenOptions::new().write(true).create_new(true).open(lock) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            let stale = fs::metadata(lock)
+                .and_then(|m| m.modified())
+                .map(|t| older(t, now, STALE_LOCK))
+                .unwrap_or(false);
+            if !stale {
+                return Ok(false);
+            }
+            fs::remove_file(lock)?;
+            // another prompt might have taken it over in the meantime
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(lock)
+                .map(|_| true)
+                .or_else(|e| match e.kind() {
+                    io::ErrorKind::AlreadyExists => Ok(false),
+                    _ => Err(e),
+                })
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// a time in the future because of a clock skew is a recent one
+fn older(t: SystemTime, now: SystemTime, than: Duration) -> bool {
+    now.duration_since(t).map(|d| d >= than).unwrap_or(false)
+}
+
+// the seconds since the epoch, rather than the modification time, so that the
+// clock can be set in the tests
+fn read(stamp: &Path) -> Option<SystemTime> {
+    let secs = fs::read_to_string(stamp).ok()?.trim().parse().ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+fn write(stamp: &Path, now: SystemTime) -> io::Result<()> {
+    let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    fs::write(stamp, secs.to_string())
+}
+
+#[cfg(test)]
+mod auto_fetch_tests {
+    use super::super::model::temp_repo;
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn rate_limited() {
+        let repo = temp_repo::repository("fetch-rate");
+        let hour = Duration::from_secs(3600);
+        let now = SystemTime::now();
+        assert!(claim(&repo, hour, now));
+        assert!(!claim(&repo, hour, now + Duration::from_secs(600)));
+        assert!(claim(&repo, hour, now + 2 * hour));
+        fs::remove_dir_all(repo.workdir().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn lock_is_respected() {
+        let repo = temp_repo::repository("fetch-lock");
+        let lock = repo.path().join(STAMP).with_extension("lock");
+        fs::write(&lock, "").unwrap();
+        let now = SystemTime::now();
+        assert!(!claim(&repo, Duration::from_secs(60), now));
+        // left behind by a prompt which was killed
+        assert!(claim(&repo, Duration::from_secs(60), now + 2 * STALE_LOCK));
+        assert!(!lock.exists());
+        fs::remove_dir_all(repo.workdir().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn ssh_in_batch_mode() {
+        assert_eq!(
+            batch_ssh(None, false).as_deref(),
+            Some("ssh -o BatchMode=yes")
+        );
+        assert_eq!(
+            batch_ssh(Some("ssh -i key".into()), false).as_deref(),
+            Some("ssh -i key -o BatchMode=yes")
+        );
+        assert_eq!(batch_ssh(None, true), None);
+    }
+
+    #[test]
+    fn fetches_from_local_remote() {
+        let remote = git2::Repository::init_bare(temp_repo::dir("fetch-remote")).unwrap();
+        let work = temp_repo::repository("fetch-work");
+        work.remote("origin", remote.path().to_str().unwrap())
+            .unwrap();
+
+        // a commit pushed to the remote by someone else
+        let other = temp_repo::repository("fetch-other");
+        let pushed = process::Command::new("git")
+            .args(["push", "--quiet"])
+            .arg(remote.path())
+            .arg("HEAD:refs/heads/topic")
+            .current_dir(other.workdir().unwrap())
+            .status()
+            .unwrap();
+        assert!(pushed.success());
+
+        let hour = Duration::from_secs(3600);
+        assert_eq!(start(&work, hour), Ok(true));
+        assert_eq!(start(&work, hour), Ok(false));
+        let fetched = (0..100).any(|_| {
+            thread::sleep(Duration::from_millis(50));
+            work.refname_to_id("refs/remotes/origin/topic").is_ok()
+        });
+        assert!(fetched);
+        for repo in &[remote, work, other] {
+            fs::remove_dir_all(repo.workdir().unwrap_or_else(|| repo.path())).unwrap();
+        }
+    }
+}
@@ -1,9 +1,15 @@
 #[macro_use]
 extern crate criterion;
 use ansi_term::Color;
-use criterion::Criterion;
+use criterion::{BatchSize, Criterion};
 
+// shared with the library, of which the benchmarks use only a part, and
+// compiled along with the helpers of the tests but without the tests
+#[allow(dead_code, unused_imports)]
 mod model;
+#[allow(dead_code, unused_imports)]
+mod untracked_cache;
+#[allow(dead_code, unused_imports)]
 mod view;
 
 fn bench_discovery(c: &mut Criterion) {
@@ -17,6 +23,7 @@ fn bench_view(c: &mut Criterion) {
                 ok: Some(Color::Green),
                 high: Some(Color::Red),
                 normal: Some(Color::Yellow),
+                shell: view::Shell::None,
             };
 
             let ss = view::StatusSymbols {
@@ -25,25 +32,51 @@ fn bench_view(c: &mut Criterion) {
                 unmerged: "✖",
                 unstaged: "✚",
                 untracked: "…",
+                stashed: "⚑",
+                unknown: "?",
+                new: "A",
+                modified: "M",
+                deleted: "D",
+                renamed: "R",
+                typechange: "T",
             };
 
             let bs = view::BranchSymbols {
                 ahead: "↑",
                 behind: "↓",
+                default_ahead: "⇡",
+                default_behind: "⇣",
+                detached: "➦",
+                in_sync: "=",
+                no_upstream: "",
+                gone: "⊘",
+                push_ahead: "⇈",
+                stale: "↻",
             };
             view::Prompt::new(&model::RepoStatus {
                 branch: Some("master".into()),
+                detached: false,
+                upstream: None,
+                remote: None,
+                last_fetch: None,
                 state: git2::RepositoryState::Clean,
+                progress: None,
+                rebase: None,
             })
             .with_branch(Some(model::BranchStatus {
-                ahead: 1,
-                behind: 4,
+                upstream: model::Upstream::Diverged {
+                    ahead: 1,
+                    behind: 4,
+                },
+                ..Default::default()
             }))
             .with_local(Some(model::LocalStatus {
                 staged: 0,
                 unmerged: 0,
                 unstaged: 0,
                 untracked: 0,
+                stashed: 0,
+                ..Default::default()
             }))
             .with_style(&c, &bs, &ss)
             .to_string()
@@ -65,18 +98,93 @@ fn git_repo() -> git2::Repository {
 fn bench_branch_status(c: &mut Criterion) {
     let r = git_repo();
     c.bench_function("branch_status", move |b| {
-        b.iter(|| model::branch_status(&r, "master", "master"))
+        b.iter(|| model::branch_status(&r, "master", Some("master"), &[], None))
+    });
+}
+
+// from HEAD to the root commit, which is as far apart as the repository allows
+fn bench_ahead_behind(c: &mut Criterion) {
+    let r = git_repo();
+    let head = r.head().unwrap().target().unwrap();
+    let mut walk = r.revwalk().unwrap();
+    walk.push(head).unwrap();
+    walk.simplify_first_parent().unwrap();
+    let root = walk.last().unwrap().unwrap();
+
+    c.bench_function("ahead_behind_full", |b| {
+        b.iter(|| r.graph_ahead_behind(head, root))
+    });
+    c.bench_function("ahead_behind_unlimited", |b| {
+        b.iter(|| model::bounded_ahead_behind(&r, head, root, usize::MAX))
+    });
+    c.bench_function("ahead_behind_limit_100", |b| {
+        b.iter(|| model::bounded_ahead_behind(&r, head, root, 100))
     });
 }
 
 fn bench_repo_status(c: &mut Criterion) {
     let r = git_repo();
-    c.bench_function("repo_status", move |b| b.iter(|| model::repo_status(&r)));
+    c.bench_function("repo_status", move |b| {
+        b.iter(|| model::repo_status(&r, &[model::Describe::Tag]))
+    });
 }
 
 fn bench_local_status(c: &mut Criterion) {
     let r = git_repo();
-    c.bench_function("local_status", move |b| b.iter(|| model::local_status(&r)));
+    c.bench_function("local_status", move |b| {
+        b.iter(|| model::local_status(&r, model::Untracked::Normal))
+    });
+}
+
+// a worktree with many untracked directories, where the untracked cache
+// makes a difference, as the worktree of git-prompt itself is too small
+fn untracked_repo() -> git2::Repository {
+    use std::{env, fs, process};
+
+    let dir = env::temp_dir().join(format!("git-prompt-bench-untracked-{}", process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let repo = git2::Repository::init(&dir).unwrap();
+    repo.config()
+        .unwrap()
+        .set_bool("core.untrackedCache", true)
+        .unwrap();
+    for i in 0..200 {
+        let sub = dir.join(format!("dir-{}", i));
+        fs::create_dir(&sub).unwrap();
+        for j in 0..20 {
+            fs::write(sub.join(format!("file-{}", j)), "").unwrap();
+        }
+    }
+    repo
+}
+
+fn bench_untracked_cache(c: &mut Criterion) {
+    use std::process::Command;
+
+    let r = untracked_repo();
+    let workdir = r.workdir().unwrap().to_owned();
+    c.bench_function("untracked_libgit2", |b| {
+        b.iter(|| model::local_status(&r, model::Untracked::Normal))
+    });
+    c.bench_function("untracked_cold", |b| {
+        b.iter_batched(
+            || {
+                Command::new("git")
+                    .args(["update-index", "--no-untracked-cache"])
+                    .current_dir(&workdir)
+                    .status()
+                    .unwrap()
+            },
+            |_| untracked_cache::local_status(&r, model::Untracked::Normal),
+            BatchSize::PerIteration,
+        )
+    });
+    // the first scan writes the cache
+    untracked_cache::local_status(&r, model::Untracked::Normal).unwrap();
+    c.bench_function("untracked_warm", |b| {
+        b.iter(|| untracked_cache::local_status(&r, model::Untracked::Normal))
+    });
+    std::fs::remove_dir_all(&workdir).unwrap();
 }
 
 criterion_group!(
@@ -84,7 +192,9 @@ criterion_group!(
     bench_discovery,
     bench_view,
     bench_branch_status,
+    bench_ahead_behind,
     bench_repo_status,
-    bench_local_status
+    bench_local_status,
+    bench_untracked_cache
 );
 criterion_main!(benches);
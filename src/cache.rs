@@ -0,0 +1,237 @@
+//! The local status of the previous prompt, stored on disk so that it can be
+//! printed right away while the worktree is scanned again.
+//!
+//! The status is only used when nothing which can be checked cheaply has
+//! changed since: HEAD, the index and the modification times of the
+//! directories with tracked files, which change when files are added or
+//! removed.  The ignored directories, e.g. the build output, are not looked
+//! at.  Changes to the content of the files are not noticed, and neither are
+//! the files added to untracked directories, so the cached status has to be
+//! refreshed after it is printed.
+use super::model::{Changes, LocalStatus, Repo, Untracked, R};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Key {
+    head: Option<String>,
+    index_mtime: Option<(u64, u32)>,
+    // the trailing SHA-1 of the index file
+    index_checksum: Option<String>,
+    // of the paths and the modification times of the directories with
+    // tracked files, which is only meant to be compared with the same binary
+    dirs: u64,
+    untracked: Untracked,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Entry {
+    key: Key,
+    staged: usize,
+    unmerged: usize,
+    unstaged: usize,
+    untracked: usize,
+    index: Changes,
+    worktree: Changes,
+    // the seconds since the epoch when it was scanned
+    #[serde(default)]
+    scanned: u64,
+}
+
+pub fn key(repo: &git2::Repository, untracked: Untracked) -> R<Key> {
+    let index = repo.path().join("index");
+    let mut hasher = DefaultHasher::new();
+    hash_dirs(repo, &mut hasher);
+    Ok(Key {
+        head: repo.refname_to_id("HEAD").ok().map(|oid| oid.to_string()),
+        index_mtime: fs::metadata(&index)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| (d.as_secs(), d.subsec_nanos())),
+        index_checksum: checksum(&index),
+        dirs: hasher.finish(),
+        untracked,
+    })
+}
+
+/// The cached status, if it was stored with the same key, along with the time
+/// it was scanned.
+pub fn lookup(repo: &git2::Repository, key: &Key) -> Option<(LocalStatus, SystemTime)> {
+    let entry: Entry = locations(repo, "git-prompt-status.json")
+        .iter()
+        .filter_map(|p| fs::read_to_string(p).ok())
+        .filter_map(|s| serde_json::from_str(&s).ok())
+        .find(|e: &Entry| &e.key == key)?;
+    let status = LocalStatus {
+        staged: entry.staged,
+        unmerged: entry.unmerged,
+        unstaged: entry.unstaged,
+        untracked: entry.untracked,
+        // the stash is cheap to count, so it is not part of the key
+        stashed: repo.stash_count().unwrap_or(0),
+        index: entry.index,
+        worktree: entry.worktree,
+    };
+    Some((status, UNIX_EPOCH + Duration::from_secs(entry.scanned)))
+}
+
+/// Store the status in the git directory, or in the cache directory of the
+/// user when the former is read-only.
+pub fn store(repo: &git2::Repository, key: &Key, status: &LocalStatus) -> R<()> {
+    let entry = Entry {
+        key: key.clone(),
+        staged: status.staged,
+        unmerged: status.unmerged,
+        unstaged: status.unstaged,
+        untracked: status.untracked,
+        index: status.index,
+        worktree: status.worktree,
+        scanned: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+    };
+    let content = serde_json::to_string(&entry).map_err(|e| format!("{:?}", e))?;
+    save(repo, "git-prompt-status.json", &content)
+}
+
+/// Save the file in the first of the locations which is writable.
+pub fn save(repo: &git2::Repository, name: &str, content: &str) -> R<()> {
+    let mut errors = Vec::new();
+    for path in locations(repo, name) {
+        match write(&path, content) {
+            Ok(()) => return Ok(()),
+            Err(e) => errors.push(format!("{}: {}", path.display(), e)),
+        }
+    }
+    Err(errors.join(", "))
+}
+
+// written to a temporary file first, as other prompts might be reading it
+fn write(path: &Path, content: &str) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+    fs::write(&tmp, content)?;
+    fs::rename(&tmp, path)
+}
+
+/// The file in the git directory and the one for the repository in
+/// $XDG_CACHE_HOME/git-prompt, where XDG_CACHE_HOME defaults to ~/.cache.
+pub fn locations(repo: &git2::Repository, name: &str) -> Vec<PathBuf> {
+    let mut paths = vec![repo.path().join(name)];
+    let cache = env::var_os("XDG_CACHE_HOME")
+        .filter(|p| !p.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".cache")));
+    if let Some(cache) = cache {
+        let mut hasher = DefaultHasher::new();
+        repo.path().hash(&mut hasher);
+        let name = format!("{:016x}-{}", hasher.finish(), name);
+        paths.push(cache.join("git-prompt").join(name));
+    }
+    paths
+}
+
+pub fn checksum(index: &Path) -> Option<String> {
+    let mut f = fs::File::open(index).ok()?;
+    f.seek(SeekFrom::End(-20)).ok()?;
+    let mut sha = [0; 20];
+    f.read_exact(&mut sha).ok()?;
+    Some(sha.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+// the directories of the tracked files rather than all of them, which would
+// include the ignored ones, e.g. target/ or node_modules/
+fn hash_dirs(repo: &git2::Repository, hasher: &mut DefaultHasher) {
+    let (workdir, index) = match (repo.workdir(), repo.index()) {
+        (Some(w), Ok(i)) => (w, i),
+        _ => return,
+    };
+    let mut dirs = BTreeSet::new();
+    dirs.insert(PathBuf::new());
+    for entry in index.iter() {
+        // lossy, as only unix allows the rare paths that are not utf-8
+        let path = String::from_utf8_lossy(&entry.path);
+        for dir in Path::new(path.as_ref()).ancestors().skip(1) {
+            // and so are the ones above it
+            if !dirs.insert(dir.to_owned()) {
+                break;
+            }
+        }
+    }
+    for dir in dirs {
+        let mtime = fs::metadata(workdir.join(&dir))
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok());
+        dir.hash(hasher);
+        mtime.hash(hasher);
+    }
+}
+
+#[cfg(test)]
+mod cache_tests {
+    use super::super::model::temp_repo::{self, repository};
+    use super::*;
+
+    #[test]
+    fn key_changes_with_new_directories() {
+        let repo = repository("cache-key");
+        let workdir = repo.workdir().unwrap().to_owned();
+        let before = key(&repo, Untracked::Normal).unwrap();
+        assert_eq!(key(&repo, Untracked::Normal), Ok(before.clone()));
+        assert_ne!(key(&repo, Untracked::None), Ok(before.clone()));
+
+        fs::create_dir_all(workdir.join("a").join("b")).unwrap();
+        assert_ne!(key(&repo, Untracked::Normal), Ok(before));
+        fs::remove_dir_all(&workdir).unwrap();
+    }
+
+    #[test]
+    fn key_skips_the_ignored_directories() {
+        let repo = repository("cache-ignored");
+        let workdir = repo.workdir().unwrap().to_owned();
+        temp_repo::commit(&repo, &[(".gitignore", "target/\n")]);
+        fs::create_dir_all(workdir.join("src")).unwrap();
+        fs::create_dir_all(workdir.join("target")).unwrap();
+        temp_repo::commit(&repo, &[("src/lib.rs", "")]);
+        let before = key(&repo, Untracked::Normal).unwrap();
+
+        fs::create_dir_all(workdir.join("target").join("debug")).unwrap();
+        assert_eq!(key(&repo, Untracked::Normal), Ok(before.clone()));
+        fs::write(workdir.join("src").join("main.rs"), "").unwrap();
+        assert_ne!(key(&repo, Untracked::Normal), Ok(before));
+        fs::remove_dir_all(&workdir).unwrap();
+    }
+
+    #[test]
+    fn lookup_needs_the_same_key() {
+        let repo = repository("cache-lookup");
+        let k = key(&repo, Untracked::Normal).unwrap();
+        let status = LocalStatus {
+            unstaged: 2,
+            untracked: 3,
+            ..Default::default()
+        };
+        assert_eq!(lookup(&repo, &k), None);
+        store(&repo, &k, &status).unwrap();
+        assert_eq!(lookup(&repo, &k).map(|(s, _)| s), Some(status));
+
+        let other = Key {
+            untracked: Untracked::All,
+            ..k
+        };
+        assert_eq!(lookup(&repo, &other), None);
+        fs::remove_dir_all(repo.workdir().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,343 @@
+use super::app::Opts;
+use super::model::R;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The settings which can be given in the config file, the git config of the
+/// repository and on the command line.  Unset values are taken from the next
+/// source when merging.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub default_branch: Option<String>,
+    pub default_branch_candidates: Option<String>,
+    pub status_symbols: Option<String>,
+    pub branch_symbols: Option<String>,
+    pub colorscheme: Option<String>,
+    pub shell: Option<String>,
+    pub layout: Option<String>,
+    // none, normal or all, which takes precedence over show_untracked
+    pub untracked: Option<String>,
+    pub show_untracked: Option<bool>,
+    // the upstream when it is not the same branch on origin
+    pub show_upstream: Option<bool>,
+    pub disable: Option<bool>,
+    // in milliseconds, 0 waits for the segments forever
+    pub timeout: Option<u64>,
+    // 0 counts all of the commits
+    pub ahead_behind_limit: Option<u64>,
+    pub describe: Option<String>,
+    // in minutes, 0 never flags the last fetch
+    pub stale_fetch: Option<u64>,
+    // in minutes, 0 never fetches in the background
+    pub auto_fetch: Option<u64>,
+}
+
+impl Config {
+    pub fn defaults() -> Config {
+        Config {
+            default_branch: None,
+            default_branch_candidates: Some("main,master,trunk,develop".into()),
+            status_symbols: Some("✔|●|✖|✚|…|⚑|?|A|M|D|R|T".into()),
+            branch_symbols: Some("↑|↓|⇡|⇣|➦|=||⊘|⇈|↻".into()),
+            colorscheme: Some("simple".into()),
+            shell: Some("none".into()),
+            layout: Some(
+                "{?state:{state} }{?branch:{branch} }{?upstream:{upstream} }\
+                 {?ahead_behind:{ahead_behind} }{?default_ahead_behind:{default_ahead_behind} }\
+                 {?fetch:{fetch} }{?local:{local} }"
+                    .into(),
+            ),
+            untracked: None,
+            show_untracked: Some(true),
+            show_upstream: Some(false),
+            disable: Some(false),
+            timeout: Some(0),
+            ahead_behind_limit: Some(0),
+            describe: Some("tag,nearest".into()),
+            stale_fetch: Some(0),
+            auto_fetch: Some(0),
+        }
+    }
+
+    pub fn from_toml(input: &str) -> R<Config> {
+        toml::from_str(input).map_err(|e| format!("{}", e))
+    }
+
+    /// Read the prompt.* section of the git config, e.g. prompt.defaultBranch,
+    /// so that the settings can be different for each repository.
+    pub fn from_git(cfg: &git2::Config) -> R<Config> {
+        fn get<T>(
+            cfg: &git2::Config,
+            name: &str,
+            f: fn(&git2::Config, &str) -> Result<T, git2::Error>,
+        ) -> R<Option<T>> {
+            match f(cfg, name) {
+                Ok(v) => Ok(Some(v)),
+                Err(ref e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+                Err(e) => Err(format!("{}: {}", name, e.message())),
+            }
+        }
+        let string = |name| get(cfg, name, git2::Config::get_string);
+        let boolean = |name| get(cfg, name, git2::Config::get_bool);
+        let number = |name| get(cfg, name, git2::Config::get_i64);
+
+        Ok(Config {
+            default_branch: string("prompt.defaultBranch")?,
+            default_branch_candidates: string("prompt.defaultBranchCandidates")?,
+            status_symbols: string("prompt.statusSymbols")?,
+            branch_symbols: string("prompt.branchSymbols")?,
+            colorscheme: string("prompt.colorscheme")?,
+            shell: string("prompt.shell")?,
+            layout: string("prompt.layout")?,
+            untracked: string("prompt.untracked")?,
+            show_untracked: boolean("prompt.showUntracked")?,
+            show_upstream: boolean("prompt.showUpstream")?,
+            disable: boolean("prompt.disable")?,
+            // a negative timeout does not make sense, so wait forever
+            timeout: number("prompt.timeout")?.map(|t| t.max(0) as u64),
+            ahead_behind_limit: number("prompt.aheadBehindLimit")?.map(|l| l.max(0) as u64),
+            describe: string("prompt.describe")?,
+            stale_fetch: number("prompt.staleFetch")?.map(|m| m.max(0) as u64),
+            auto_fetch: number("prompt.autoFetch")?.map(|m| m.max(0) as u64),
+        })
+    }
+
+    /// Read the prompt.* section from the given levels of the git config, the
+    /// later ones taking precedence, e.g. GLOBAL or LOCAL.
+    pub fn from_git_levels(cfg: &git2::Config, levels: &[git2::ConfigLevel]) -> R<Config> {
+        let mut merged = Config::default();
+        for level in levels {
+            match cfg.open_level(*level) {
+                Ok(c) => merged = merged.merge(Config::from_git(&c)?),
+                Err(ref e) if e.code() == git2::ErrorCode::NotFound => {}
+                Err(e) => return Err(format!("{:?}", e)),
+            }
+        }
+        Ok(merged)
+    }
+
+    /// Load the config from the given path, which has to exist, or from the
+    /// default location, which may be missing.
+    pub fn load(path: Option<&Path>) -> R<Config> {
+        let path = match path {
+            Some(p) => p.to_owned(),
+            None => match default_path().filter(|p| p.is_file()) {
+                Some(p) => p,
+                None => return Ok(Config::default()),
+            },
+        };
+        fs::read_to_string(&path)
+            .map_err(|e| format!("{}", e))
+            .and_then(|s| Config::from_toml(&s))
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Merge the other config on top of this one.
+    pub fn merge(self, other: Config) -> Config {
+        Config {
+            default_branch: other.default_branch.or(self.default_branch),
+            default_branch_candidates: other
+                .default_branch_candidates
+                .or(self.default_branch_candidates),
+            status_symbols: other.status_symbols.or(self.status_symbols),
+            branch_symbols: other.branch_symbols.or(self.branch_symbols),
+            colorscheme: other.colorscheme.or(self.colorscheme),
+            shell: other.shell.or(self.shell),
+            layout: other.layout.or(self.layout),
+            untracked: other.untracked.or(self.untracked),
+            show_untracked: other.show_untracked.or(self.show_untracked),
+            show_upstream: other.show_upstream.or(self.show_upstream),
+            disable: other.disable.or(self.disable),
+            timeout: other.timeout.or(self.timeout),
+            ahead_behind_limit: other.ahead_behind_limit.or(self.ahead_behind_limit),
+            describe: other.describe.or(self.describe),
+            stale_fetch: other.stale_fetch.or(self.stale_fetch),
+            auto_fetch: other.auto_fetch.or(self.auto_fetch),
+        }
+    }
+}
+
+impl From<&Opts> for Config {
+    fn from(opts: &Opts) -> Config {
+        Config {
+            default_branch: opts.default_branch.clone(),
+            default_branch_candidates: opts.default_branch_candidates.clone(),
+            status_symbols: opts.status_symbols.clone(),
+            branch_symbols: opts.branch_symbols.clone(),
+            colorscheme: opts.colorscheme.clone(),
+            shell: opts.shell.clone(),
+            layout: opts.layout.clone(),
+            untracked: opts.untracked.clone(),
+            timeout: opts.timeout,
+            ahead_behind_limit: opts.ahead_behind_limit,
+            describe: opts.describe.clone(),
+            stale_fetch: opts.stale_fetch,
+            auto_fetch: opts.auto_fetch,
+            ..Default::default()
+        }
+    }
+}
+
+/// The levels of the git config which belong to the user rather than to the
+/// repository, so they give way to the config file.
+pub const GLOBAL: &[git2::ConfigLevel] = &[
+    git2::ConfigLevel::ProgramData,
+    git2::ConfigLevel::System,
+    git2::ConfigLevel::XDG,
+    git2::ConfigLevel::Global,
+];
+
+/// The levels of the git config of the repository, which take precedence over
+/// the config file.
+pub const LOCAL: &[git2::ConfigLevel] = &[git2::ConfigLevel::Local, git2::ConfigLevel::App];
+
+/// status.showUntrackedFiles, which applies to git status too, so it is only
+/// used when neither prompt.untracked nor prompt.showUntracked is set.
+pub fn show_untracked_files(cfg: &git2::Config) -> R<Option<String>> {
+    match cfg.get_string("status.showUntrackedFiles") {
+        Ok(v) => Ok(Some(v)),
+        Err(ref e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(format!("status.showUntrackedFiles: {}", e.message())),
+    }
+}
+
+/// $XDG_CONFIG_HOME/git-prompt/config.toml, where XDG_CONFIG_HOME defaults to
+/// ~/.config.
+pub fn default_path() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(|p| !p.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))
+        .map(|p| p.join("git-prompt").join("config.toml"))
+}
+
+#[cfg(test)]
+mod config_tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn from_toml() {
+        let c = Config::from_toml(
+            r#"
+            default-branch = "trunk"
+            status-symbols = "ok|s|m|u|?"
+            layout = "{branch} "
+            "#,
+        );
+        assert_eq!(
+            c,
+            Ok(Config {
+                default_branch: Some("trunk".into()),
+                status_symbols: Some("ok|s|m|u|?".into()),
+                layout: Some("{branch} ".into()),
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn unknown_keys_are_errors() {
+        let e = Config::from_toml("colourscheme = \"simple\"").unwrap_err();
+        assert!(e.contains("colourscheme"), "{}", e);
+    }
+
+    #[test]
+    fn wrong_types_are_errors() {
+        assert!(Config::from_toml("shell = 1").is_err());
+    }
+
+    #[test]
+    fn merge_takes_the_set_values() {
+        let file = Config {
+            colorscheme: Some("1,2,3".into()),
+            shell: Some("zsh".into()),
+            ..Default::default()
+        };
+        let cli = Config {
+            shell: Some("bash".into()),
+            ..Default::default()
+        };
+        let c = Config::defaults().merge(file).merge(cli);
+        assert_eq!(c.colorscheme, Some("1,2,3".into()));
+        assert_eq!(c.shell, Some("bash".into()));
+        assert_eq!(c.branch_symbols, Config::defaults().branch_symbols);
+    }
+
+    fn git_config(name: &str, content: &str) -> git2::Config {
+        let path = env::temp_dir().join(format!("git-prompt-{}-{}", process::id(), name));
+        fs::write(&path, content).unwrap();
+        let cfg = git2::Config::open(&path).unwrap().snapshot();
+        let cfg = cfg.unwrap();
+        fs::remove_file(&path).unwrap();
+        cfg
+    }
+
+    #[test]
+    fn from_git() {
+        let cfg = git_config(
+            "from_git",
+            "[prompt]\n\tdefaultBranch = develop\n\tshowUntracked = false\n\ttimeout = 1k\n",
+        );
+        assert_eq!(
+            Config::from_git(&cfg),
+            Ok(Config {
+                default_branch: Some("develop".into()),
+                show_untracked: Some(false),
+                timeout: Some(1024),
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn from_git_untracked() {
+        let cfg = git_config(
+            "untracked",
+            "[status]\n\tshowUntrackedFiles = no\n[prompt]\n\tshowUntracked = true\n",
+        );
+        assert_eq!(show_untracked_files(&cfg), Ok(Some("no".into())));
+        let c = Config::from_git(&cfg).unwrap();
+        assert_eq!((c.untracked, c.show_untracked), (None, Some(true)));
+    }
+
+    #[test]
+    fn from_git_levels() {
+        let dir = env::temp_dir().join(format!("git-prompt-{}-levels", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let global = dir.join("global");
+        let local = dir.join("local");
+        fs::write(&global, "[prompt]\n\tshell = zsh\n\tlayout = global\n").unwrap();
+        fs::write(&local, "[prompt]\n\tlayout = local\n").unwrap();
+        let mut cfg = git2::Config::new().unwrap();
+        cfg.add_file(&global, git2::ConfigLevel::Global, false)
+            .unwrap();
+        cfg.add_file(&local, git2::ConfigLevel::Local, false)
+            .unwrap();
+
+        let g = Config::from_git_levels(&cfg, GLOBAL).unwrap();
+        assert_eq!(
+            (g.shell, g.layout),
+            (Some("zsh".into()), Some("global".into()))
+        );
+        let l = Config::from_git_levels(&cfg, LOCAL).unwrap();
+        assert_eq!((l.shell, l.layout), (None, Some("local".into())));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn from_git_invalid_bool() {
+        let cfg = git_config("from_git_invalid_bool", "[prompt]\n\tdisable = maybe\n");
+        let e = Config::from_git(&cfg).unwrap_err();
+        assert!(e.starts_with("prompt.disable"), "{}", e);
+    }
+
+    #[test]
+    fn missing_explicit_file() {
+        let e = Config::load(Some(Path::new("/nonexistent/git-prompt.toml"))).unwrap_err();
+        assert!(e.starts_with("/nonexistent/git-prompt.toml"), "{}", e);
+    }
+}
@@ -0,0 +1,550 @@
+//! A long running process which keeps the repositories open and caches their
+//! local status, which is the expensive part of the prompt in large
+//! repositories.  The cache is invalidated by filesystem events, apart from
+//! the ones for ignored files, e.g. the build output.
+//!
+//! The protocol is a single line of JSON with the request from the client,
+//! which is answered with the lines of the prompt, as they would be printed
+//! without the daemon.
+use super::app::Opts;
+use super::config::Config;
+use super::model::{self, LocalStatus, Untracked, R};
+use super::Update;
+use notify::{RawEvent, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+
+/// What to print for which repository.  The flags are resolved by the client
+/// as they might come from its environment and the paths are absolute, as
+/// the daemon runs elsewhere.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct Request {
+    pub path: PathBuf,
+    pub config: Option<PathBuf>,
+    pub flags: Config,
+    pub format: String,
+    pub print_updates: bool,
+}
+
+impl Request {
+    pub fn new(opts: &Opts) -> R<Request> {
+        let cwd = env::current_dir().map_err(|e| format!("{:?}", e))?;
+        Ok(Request {
+            path: cwd.join(&opts.path),
+            config: opts.config.as_ref().map(|p| cwd.join(p)),
+            flags: Config::from(opts),
+            format: opts.format.clone(),
+            print_updates: opts.print_updates,
+        })
+    }
+}
+
+/// The socket given on the command line or $XDG_RUNTIME_DIR/git-prompt.sock,
+/// falling back to a directory of the user in the temporary directory.
+pub fn socket(path: Option<&Path>) -> R<PathBuf> {
+    if let Some(p) = path {
+        return Ok(p.to_owned());
+    }
+    let dir = match env::var_os("XDG_RUNTIME_DIR").filter(|d| !d.is_empty()) {
+        Some(d) => PathBuf::from(d),
+        None => {
+            let d = env::temp_dir().join(format!("git-prompt-{}", uid()));
+            // it might exist already, which is checked below
+            let _ = fs::DirBuilder::new().mode(0o700).create(&d);
+            d
+        }
+    };
+    private(&dir, uid())?;
+    Ok(dir.join("git-prompt.sock"))
+}
+
+/// Connect to the daemon of the user.  The answer ends up in the prompt, which
+/// e.g. zsh expands, so a socket of another user is refused.
+pub fn connect(socket: &Path) -> R<UnixStream> {
+    owned(socket, uid())?;
+    UnixStream::connect(socket).map_err(|e| format!("{}: {}", socket.display(), e))
+}
+
+fn uid() -> u32 {
+    // which cannot fail
+    unsafe { libc::getuid() }
+}
+
+fn owned(path: &Path, uid: u32) -> R<fs::Metadata> {
+    let m = fs::symlink_metadata(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    if m.uid() != uid {
+        return Err(format!("{}: owned by another user", path.display()));
+    }
+    Ok(m)
+}
+
+// otherwise another user could create the socket in the directory before the
+// daemon does
+fn private(dir: &Path, uid: u32) -> R<()> {
+    let m = owned(dir, uid)?;
+    if !m.is_dir() || m.mode() & 0o077 != 0 {
+        return Err(format!(
+            "{}: not a directory only the user can access, use --socket",
+            dir.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Send the request and copy the answer to the output as it arrives.
+pub fn query(mut stream: UnixStream, req: &Request, out: &mut dyn Write) -> R<()> {
+    let line = serde_json::to_string(req).map_err(|e| format!("{:?}", e))?;
+    writeln!(stream, "{}", line).map_err(|e| format!("{:?}", e))?;
+    match io::copy(&mut stream, out) {
+        // the daemon does not answer when it fails to compute the prompt
+        Ok(0) => Err("no answer from the daemon".into()),
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("{:?}", e)),
+    }
+}
+
+pub fn serve(socket: &Path) -> R<()> {
+    if UnixStream::connect(socket).is_ok() {
+        return Err(format!(
+            "{}: the daemon is already running",
+            socket.display()
+        ));
+    }
+    // left behind by a daemon which did not exit cleanly
+    let _ = fs::remove_file(socket);
+    let listener =
+        UnixListener::bind(socket).map_err(|e| format!("{}: {}", socket.display(), e))?;
+
+    let daemon = Arc::new(Daemon::new()?);
+    for stream in listener.incoming() {
+        // so that a slow repository or client does not hold up the others
+        let daemon = daemon.clone();
+        thread::spawn(move || {
+            let result = stream
+                .map_err(|e| format!("{:?}", e))
+                .and_then(|s| handle(&daemon, s));
+            if let Err(e) = result {
+                eprintln!("git-prompt: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Answer the request of a client.  The prompt is computed with a handle of
+/// its own, so the daemon is only locked to look up the repository.
+pub fn handle(daemon: &Daemon, mut stream: UnixStream) -> R<()> {
+    let mut line = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut line)
+        .map_err(|e| format!("{:?}", e))?;
+    let req: Request = serde_json::from_str(&line).map_err(|e| format!("{:?}", e))?;
+
+    let (key, shared) = daemon.open(&req.path)?;
+    let repo = git2::Repository::open(&key).map_err(|e| format!("{:?}", e))?;
+    let uncached = |r: &git2::Repository, untracked, tx: Sender<Update>| {
+        super::spawn(r, move |r| {
+            let _ = tx.send(Update::Local(model::local_status(r, untracked)));
+        })
+    };
+    let cached = move |r: &git2::Repository, untracked, tx: Sender<Update>| {
+        let shared = match shared {
+            Some(s) => s,
+            // the changes cannot be watched, so it cannot be cached either
+            None => return uncached(r, untracked, tx),
+        };
+        let mut guard = shared.lock().expect("the cache is not poisoned");
+        let local = &mut *guard;
+        match (&local.status, &mut local.waiting) {
+            (Some((u, s)), _) if *u == untracked => {
+                let _ = tx.send(Update::Local(s.clone()));
+                return;
+            }
+            // e.g. the computation the previous request gave up on
+            (_, Some((u, waiting))) if *u == untracked => {
+                waiting.push(tx);
+                return;
+            }
+            // the requests waiting for the other kind are still answered
+            (_, Some(_)) => return uncached(r, untracked, tx),
+            _ => {}
+        }
+        local.waiting = Some((untracked, vec![tx]));
+        scan(r, shared.clone(), untracked, local.generation);
+    };
+    super::prompt(&req, &repo, cached, &mut stream)
+}
+
+// compute the local status for the requests which are waiting for it
+fn scan(r: &git2::Repository, shared: Arc<Mutex<Local>>, untracked: Untracked, generation: usize) {
+    let mut generation = generation;
+    super::spawn(r, move |r| loop {
+        let s = model::local_status(r, untracked);
+        let mut local = shared.lock().expect("the cache is not poisoned");
+        // it changed during the scan, which is repeated for the requests
+        // waiting for it then
+        if local.generation != generation {
+            generation = local.generation;
+            continue;
+        }
+        if let Some((_, waiting)) = local.waiting.take() {
+            for tx in waiting {
+                let _ = tx.send(Update::Local(s.clone()));
+            }
+        }
+        local.status = Some((untracked, s));
+        return;
+    });
+}
+
+struct Entry {
+    // to tell the ignored files apart
+    repo: git2::Repository,
+    // the worktree, or the git directory of bare repositories
+    key: PathBuf,
+    // the directories which are watched, the worktree and the git directory
+    dirs: Vec<PathBuf>,
+    // shared with the thread computing it, and none when the directories
+    // could not be watched, e.g. because of the limit of inotify watches
+    local: Option<Arc<Mutex<Local>>>,
+}
+
+impl Entry {
+    // the changes of the ignored files do not change the status, unless they
+    // are in the git directory, e.g. .git/index
+    fn changed_by(&self, path: &Path) -> bool {
+        if !self.dirs.iter().any(|d| path.starts_with(d)) {
+            return false;
+        }
+        match path.strip_prefix(&self.key) {
+            Ok(p) if !path.starts_with(self.repo.path()) => {
+                !self.repo.is_path_ignored(p).unwrap_or(false)
+            }
+            _ => true,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Local {
+    // increased on every change, so that the results of the computations
+    // which started before are dropped
+    generation: usize,
+    // along with whether the untracked files were counted
+    status: Option<(Untracked, LocalStatus)>,
+    // the computation in progress and the requests waiting for it, which
+    // might have given up already
+    waiting: Option<(Untracked, Vec<Sender<Update>>)>,
+}
+
+impl Local {
+    // the requests which are waiting keep waiting for the scan, which starts
+    // over when it is done
+    fn changed(&mut self) {
+        self.generation += 1;
+        self.status = None;
+    }
+}
+
+pub struct Daemon {
+    state: Mutex<State>,
+    // watching a large worktree takes a while, which would hold up the
+    // requests for the other repositories with the state locked
+    watcher: Mutex<RecommendedWatcher>,
+}
+
+struct State {
+    // keyed by the worktree, or the git directory of bare repositories
+    repos: HashMap<PathBuf, Entry>,
+    // the requested paths, which can be anywhere in the worktree
+    paths: HashMap<PathBuf, PathBuf>,
+    events: Receiver<RawEvent>,
+}
+
+// the worktree and the cached local status of a repository
+type Opened = (PathBuf, Option<Arc<Mutex<Local>>>);
+
+impl Daemon {
+    pub fn new() -> R<Daemon> {
+        let (tx, rx) = channel();
+        Ok(Daemon {
+            state: Mutex::new(State {
+                repos: HashMap::new(),
+                paths: HashMap::new(),
+                events: rx,
+            }),
+            watcher: Mutex::new(notify::raw_watcher(tx).map_err(|e| format!("{:?}", e))?),
+        })
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("the daemon is not poisoned")
+    }
+
+    /// The repository of the path, which is discovered and watched on the
+    /// first request for it without the state locked.
+    fn open(&self, path: &Path) -> R<Opened> {
+        let opened = |state: &State, key: &Path| -> Option<Opened> {
+            let entry = state.repos.get(key)?;
+            Some((entry.key.clone(), entry.local.clone()))
+        };
+        {
+            let mut state = self.state();
+            state.invalidate();
+            if let Some(o) = state.paths.get(path).and_then(|k| opened(&state, k)) {
+                return Ok(o);
+            }
+        }
+
+        let repo = git2::Repository::discover(path).map_err(|e| format!("{:?}", e))?;
+        let key = repo.workdir().unwrap_or_else(|| repo.path()).to_owned();
+        // one repository is watched at a time, so that two requests for the
+        // same one do not both watch it
+        let mut watcher = self.watcher.lock().expect("the watcher is not poisoned");
+        let known = opened(&self.state(), &key);
+        if let Some(o) = known {
+            self.state().paths.insert(path.to_owned(), key);
+            return Ok(o);
+        }
+        let mut dirs = vec![key.clone()];
+        // the git directory of linked worktrees is elsewhere
+        if !repo.path().starts_with(&key) {
+            dirs.push(repo.path().to_owned());
+        }
+        let local = Some(Arc::default()).filter(|_| watch(&mut watcher, &dirs));
+        let mut state = self.state();
+        state.repos.insert(
+            key.clone(),
+            Entry {
+                repo,
+                key: key.clone(),
+                dirs,
+                local: local.clone(),
+            },
+        );
+        state.paths.insert(path.to_owned(), key.clone());
+        Ok((key, local))
+    }
+}
+
+// all of the directories or none of them, as a change in any of them might be
+// missed otherwise
+fn watch(watcher: &mut RecommendedWatcher, dirs: &[PathBuf]) -> bool {
+    for (i, d) in dirs.iter().enumerate() {
+        if let Err(e) = watcher.watch(d, RecursiveMode::Recursive) {
+            eprintln!("git-prompt: {}: {:?}", d.display(), e);
+            for watched in &dirs[..i] {
+                let _ = watcher.unwatch(watched);
+            }
+            // a failed recursive watch leaves the watches of the
+            // subdirectories behind
+            let _ = watcher.unwatch(d);
+            return false;
+        }
+    }
+    true
+}
+
+impl State {
+    /// Forget the local status of the repositories which changed since the
+    /// last request.
+    fn invalidate(&mut self) {
+        for event in self.events.try_iter() {
+            for entry in self.repos.values_mut() {
+                let local = match &entry.local {
+                    Some(l) => l,
+                    None => continue,
+                };
+                match &event.path {
+                    Some(p) if !entry.changed_by(p) => {}
+                    // events without a path are errors, e.g. dropped events
+                    _ => local.lock().expect("the cache is not poisoned").changed(),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod daemon_tests {
+    use super::super::model::temp_repo;
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn request(path: &Path) -> Request {
+        Request {
+            path: path.to_owned(),
+            // not to depend on the config file of the user
+            config: Some(path.join("git-prompt.toml")),
+            flags: Config::default(),
+            format: "json".into(),
+            print_updates: false,
+        }
+    }
+
+    fn ask(daemon: &Daemon, req: &Request) -> String {
+        let (client, server) = UnixStream::pair().unwrap();
+        let line = serde_json::to_string(req).unwrap();
+        writeln!(&client, "{}", line).unwrap();
+        handle(daemon, server).unwrap();
+        let mut answer = String::new();
+        BufReader::new(client).read_line(&mut answer).unwrap();
+        answer
+    }
+
+    #[test]
+    fn only_private_directories() {
+        let dir = temp_repo::dir("daemon-private");
+        fs::DirBuilder::new().mode(0o700).create(&dir).unwrap();
+        assert_eq!(private(&dir, uid()), Ok(()));
+        assert!(private(&dir, uid() + 1).is_err());
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(private(&dir, uid()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_own_sockets() {
+        let dir = temp_repo::dir("daemon-owner");
+        fs::create_dir(&dir).unwrap();
+        let socket = dir.join("git-prompt.sock");
+        let _listener = UnixListener::bind(&socket).unwrap();
+        assert!(connect(&socket).is_ok());
+        assert!(owned(&socket, uid()).is_ok());
+        assert!(owned(&socket, uid() + 1).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn request_round_trip() {
+        let req = Request {
+            flags: Config {
+                shell: Some("zsh".into()),
+                ..Default::default()
+            },
+            ..request(&env::temp_dir())
+        };
+        let line = serde_json::to_string(&req).unwrap();
+        assert_eq!(serde_json::from_str::<Request>(&line).unwrap(), req);
+    }
+
+    // with a commit and the empty config file, which is untracked
+    fn repository(name: &str) -> PathBuf {
+        let repo = temp_repo::repository(&format!("daemon-{}", name));
+        let dir = repo.workdir().unwrap().to_owned();
+        fs::write(request(&dir).config.unwrap(), "").unwrap();
+        dir
+    }
+
+    #[test]
+    fn timed_out_status_is_kept() {
+        let dir = repository("timed_out");
+        let req = Request {
+            flags: Config {
+                timeout: Some(1),
+                ..Default::default()
+            },
+            ..request(&dir)
+        };
+        let daemon = Daemon::new().unwrap();
+
+        // the computation carries on after the requests give up on it
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut answer = ask(&daemon, &req);
+        while !answer.contains(r#""untracked":1"#) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+            answer = ask(&daemon, &req);
+        }
+        assert!(answer.contains(r#""untracked":1"#), "{}", answer);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cache_is_invalidated_on_changes() {
+        let dir = repository("invalidated");
+        let req = request(&dir);
+        let daemon = Daemon::new().unwrap();
+
+        let before = ask(&daemon, &req);
+        assert!(before.contains(r#""untracked":1"#), "{}", before);
+        assert_eq!(ask(&daemon, &req), before);
+
+        fs::write(dir.join("new"), "").unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut after = ask(&daemon, &req);
+        while after == before && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+            after = ask(&daemon, &req);
+        }
+        assert!(after.contains(r#""untracked":2"#), "{}", after);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn change_during_the_scan() {
+        let dir = repository("during-scan");
+        let repo = git2::Repository::open(&dir).unwrap();
+        let local = Arc::new(Mutex::new(Local::default()));
+        let (tx, rx) = channel();
+
+        // the scan cannot store its result while the cache is locked
+        let mut guard = local.lock().unwrap();
+        guard.waiting = Some((Untracked::Normal, vec![tx]));
+        scan(&repo, local.clone(), Untracked::Normal, guard.generation);
+        fs::write(dir.join("new"), "").unwrap();
+        guard.changed();
+        drop(guard);
+
+        match rx.recv_timeout(Duration::from_secs(5)) {
+            Ok(Update::Local(s)) => assert_eq!(s.untracked, 2),
+            _ => panic!("no status for the waiting request"),
+        }
+        assert!(local.lock().unwrap().status.is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ignored_changes_are_skipped() {
+        let dir = repository("ignored");
+        fs::write(dir.join(".gitignore"), "build/\n").unwrap();
+        fs::create_dir(dir.join("build")).unwrap();
+        let req = request(&dir);
+        let daemon = Daemon::new().unwrap();
+
+        let cached = || {
+            let local = daemon.open(&dir).unwrap().1.unwrap();
+            let cached = local.lock().unwrap().status.is_some();
+            cached
+        };
+        // the status is stored after it is sent, and the first scan might
+        // refresh the index
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut before = ask(&daemon, &req);
+        while !cached() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+            before = ask(&daemon, &req);
+        }
+        assert!(cached());
+
+        fs::write(dir.join("build").join("output"), "").unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert!(cached());
+        assert_eq!(ask(&daemon, &req), before);
+
+        fs::write(dir.join("new"), "").unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert!(!cached());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -8,32 +8,75 @@ pub fn all<'a>() -> Examples<'a> {
     let master = Some("master".to_owned());
 
     fn b(ahead: usize, behind: usize) -> Option<model::BranchStatus> {
-        Some(model::BranchStatus { ahead, behind })
+        Some(model::BranchStatus {
+            upstream: (ahead, behind).into(),
+            ..Default::default()
+        })
+    }
+    fn d(ahead: usize, behind: usize, default_behind: usize) -> Option<model::BranchStatus> {
+        Some(model::BranchStatus {
+            upstream: (ahead, behind).into(),
+            default_behind,
+            default_branch: Some(model::DefaultBranch::RemoteHead("master".to_owned())),
+            ..Default::default()
+        })
     }
-    fn s(staged: usize, unstaged: usize, unmerged: usize, untracked: usize) -> model::LocalStatus {
+    fn s(
+        staged: usize,
+        unstaged: usize,
+        unmerged: usize,
+        untracked: usize,
+        stashed: usize,
+    ) -> model::LocalStatus {
         model::LocalStatus {
             staged,
             unstaged,
             unmerged,
             untracked,
-            ..Default::default()
+            stashed,
+            index: model::Changes {
+                modified: staged,
+                ..Default::default()
+            },
+            worktree: model::Changes {
+                modified: unstaged,
+                ..Default::default()
+            },
         }
     }
 
     Examples::new()
-        .with("after 'git init'", None, Clean, None, s(0, 3, 0, 0))
-        .with("ok", master.clone(), Clean, b(0, 0), s(0, 0, 0, 0))
-        .with("stage", master.clone(), Clean, b(0, 0), s(3, 0, 0, 0))
-        .with("partial", master.clone(), Clean, b(0, 0), s(3, 12, 0, 0))
+        .with("after 'git init'", None, Clean, None, s(0, 3, 0, 0, 0))
+        .with("ok", master.clone(), Clean, b(0, 0), s(0, 0, 0, 0, 0))
+        .with("stashed", master.clone(), Clean, b(0, 0), s(0, 0, 0, 0, 2))
+        .with("stage", master.clone(), Clean, b(0, 0), s(3, 0, 0, 0, 0))
+        .with("partial", master.clone(), Clean, b(0, 0), s(3, 12, 0, 0, 0))
         .with(
             "conflicts",
             Some("a83e2a3f".to_owned()),
             Rebase,
             b(0, 3),
-            s(0, 2, 1, 0),
+            s(0, 2, 1, 0, 0),
+        )
+        .with(
+            "feature",
+            Some("feature".to_owned()),
+            Clean,
+            d(2, 0, 5),
+            s(0, 0, 0, 0, 0),
+        )
+        .with(
+            "gone",
+            Some("merged".to_owned()),
+            Clean,
+            Some(model::BranchStatus {
+                upstream: model::Upstream::Gone,
+                ..Default::default()
+            }),
+            s(0, 0, 0, 0, 0),
         )
-        .with("rebase", master.clone(), Rebase, b(0, 3), s(0, 3, 0, 0))
-        .with("diverged", master, Rebase, b(12, 3), s(0, 0, 0, 3))
+        .with("rebase", master.clone(), Rebase, b(0, 3), s(0, 3, 0, 0, 0))
+        .with("diverged", master, Rebase, b(12, 3), s(0, 0, 0, 3, 0))
 }
 
 pub struct Examples<'a> {
@@ -58,9 +101,18 @@ impl<'a> Examples<'a> {
     ) -> Examples<'a> {
         self.examples.insert(
             key.to_string(),
-            view::Prompt::new(&model::RepoStatus { branch: br, state })
-                .with_branch(branch)
-                .with_local(Some(local)),
+            view::Prompt::new(&model::RepoStatus {
+                branch: br,
+                detached: false,
+                upstream: None,
+                remote: None,
+                last_fetch: None,
+                state,
+                progress: None,
+                rebase: None,
+            })
+            .with_branch(branch)
+            .with_local(Some(local)),
         );
         self
     }
@@ -78,6 +130,15 @@ impl<'a> Examples<'a> {
             .collect();
         self
     }
+
+    pub fn with_layout(mut self, layout: &view::Layout) -> Examples<'a> {
+        self.examples = self
+            .examples
+            .iter()
+            .map(|(l, p)| (l.to_owned(), p.with_layout(layout)))
+            .collect();
+        self
+    }
 }
 
 impl<'a> Display for Examples<'a> {
@@ -0,0 +1,302 @@
+//! Asks the core.fsmonitor hook of the repository, e.g. the watchman one git
+//! comes with, which files changed since the previous prompt, so that only
+//! those are looked at again instead of scanning the whole worktree.
+//!
+//! The statuses of the paths which are not current are kept along with the
+//! token of the hook.  The worktree is scanned when HEAD or the index changed,
+//! which change the statuses of the files without touching them, when the
+//! hook does not know what changed or when an untracked file changed, as
+//! those are counted per directory.
+use super::cache;
+use super::model::{self, LocalStatus, Repo, Untracked, R};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const STATE: &str = "git-prompt-fsmonitor.json";
+
+// the statuses which only change along with the index
+const STAGED: git2::Status = git2::Status::from_bits_truncate(
+    git2::Status::INDEX_NEW.bits()
+        | git2::Status::INDEX_MODIFIED.bits()
+        | git2::Status::INDEX_DELETED.bits()
+        | git2::Status::INDEX_RENAMED.bits()
+        | git2::Status::INDEX_TYPECHANGE.bits(),
+);
+
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+struct State {
+    token: String,
+    head: Option<String>,
+    index_checksum: Option<String>,
+    untracked: Untracked,
+    // the statuses of the paths which are not current
+    paths: BTreeMap<String, u32>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Changes {
+    // the hook does not know what changed since the token
+    All,
+    Paths(Vec<String>),
+}
+
+/// The core.fsmonitor hook, unless it is a boolean for the builtin monitor of
+/// git, which is not supported.
+pub fn hook(repo: &git2::Repository) -> Option<String> {
+    let cfg = repo.config().ok()?;
+    if cfg.get_bool("core.fsmonitor").is_ok() {
+        return None;
+    }
+    cfg.get_string("core.fsmonitor")
+        .ok()
+        .filter(|h| !h.is_empty())
+}
+
+/// Run the hook like git does, through the shell in the worktree, and return
+/// the new token with the paths which changed since the given one.  Version 1
+/// of the protocol takes a timestamp in nanoseconds instead of a token.
+pub fn query(hook: &str, workdir: &Path, version: i64, token: &str) -> R<(String, Changes)> {
+    let (token, next) = match version {
+        1 => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos().to_string())
+                .unwrap_or_default();
+            // the first query has to return everything
+            let since = if token.is_empty() { "0" } else { token };
+            (since.to_owned(), Some(now))
+        }
+        2 => (token.to_owned(), None),
+        v => return Err(format!("unsupported fsmonitor hook version {}", v)),
+    };
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", hook))
+        .arg(hook)
+        .arg(version.to_string())
+        .arg(token)
+        .current_dir(workdir)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|e| format!("{}: {}", hook, e))?;
+    if !output.status.success() {
+        return Err(format!("{}: {}", hook, output.status));
+    }
+
+    let out = String::from_utf8_lossy(&output.stdout);
+    let mut parts = out.split('\0').filter(|p| !p.is_empty());
+    let next = match next {
+        Some(n) => n,
+        None => parts
+            .next()
+            .ok_or_else(|| format!("{}: no token", hook))?
+            .to_owned(),
+    };
+    let paths: Vec<String> = parts.map(str::to_owned).collect();
+    if paths.iter().any(|p| p == "/") {
+        return Ok((next, Changes::All));
+    }
+    Ok((next, Changes::Paths(paths)))
+}
+
+/// The local status, updated with the changes the hook reports.
+pub fn local_status(repo: &git2::Repository, hook: &str, untracked: Untracked) -> LocalStatus {
+    let workdir = match repo.workdir() {
+        Some(w) => w,
+        None => return model::local_status(repo, untracked),
+    };
+    let previous: Option<State> = cache::locations(repo, STATE)
+        .iter()
+        .filter_map(|p| fs::read_to_string(p).ok())
+        .find_map(|s| serde_json::from_str(&s).ok());
+    let version = repo
+        .config()
+        .and_then(|c| c.get_i64("core.fsmonitorHookVersion"))
+        .unwrap_or(2);
+    let token = previous.as_ref().map(|s| s.token.as_str()).unwrap_or("");
+
+    // before looking at the files, so that the changes made in the meantime
+    // are reported the next time
+    let response = query(hook, workdir, version, token);
+    let head = repo.refname_to_id("HEAD").ok().map(|oid| oid.to_string());
+    let index_checksum = cache::checksum(&repo.path().join("index"));
+
+    let paths = match (previous, &response) {
+        (Some(s), Ok((_, changes)))
+            if s.head == head && s.index_checksum == index_checksum && s.untracked == untracked =>
+        {
+            update(repo, s.paths, changes, untracked)
+        }
+        _ => None,
+    }
+    .unwrap_or_else(|| scan(repo, untracked));
+
+    let mut status = model::count(paths.values().map(|b| git2::Status::from_bits_truncate(*b)));
+    status.stashed = repo.stash_count().unwrap_or(0);
+    if let Ok((token, _)) = response {
+        let state = State {
+            token,
+            head,
+            index_checksum,
+            untracked,
+            paths,
+        };
+        if let Ok(content) = serde_json::to_string(&state) {
+            let _ = cache::save(repo, STATE, &content);
+        }
+    }
+    status
+}
+
+fn scan(repo: &git2::Repository, untracked: Untracked) -> BTreeMap<String, u32> {
+    repo.statuses(Some(&mut model::status_options(untracked)))
+        .map(|statuses| {
+            statuses
+                .iter()
+                // by the path in the index, which is the new one of a rename
+                .filter_map(|e| {
+                    let new = e.head_to_index().and_then(|d| d.new_file().path());
+                    let path = new.and_then(Path::to_str).or_else(|| e.path());
+                    path.map(|p| (p.to_owned(), e.status().bits()))
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// None when the worktree has to be scanned
+fn update(
+    repo: &git2::Repository,
+    mut paths: BTreeMap<String, u32>,
+    changes: &Changes,
+    untracked: Untracked,
+) -> Option<BTreeMap<String, u32>> {
+    let changed = match changes {
+        Changes::All => return None,
+        Changes::Paths(p) => p,
+    };
+    let index = repo.index().ok()?;
+    for p in changed.iter().filter(|p| !p.starts_with(".git/")) {
+        let path = Path::new(p.trim_end_matches('/'));
+        if index.get_path(path, 0).is_some() {
+            // the file alone does not know that it was renamed in the index
+            let staged = paths
+                .get(p)
+                .map(|b| git2::Status::from_bits_truncate(*b) & STAGED)
+                .unwrap_or_else(git2::Status::empty);
+            match (repo.status_file(path).ok()? - STAGED) | staged {
+                s if s.is_empty() => paths.remove(p),
+                s => paths.insert(p.to_owned(), s.bits()),
+            };
+        } else if untracked != Untracked::None && !repo.status_should_ignore(path).unwrap_or(false)
+        {
+            return None;
+        }
+    }
+    Some(paths)
+}
+
+#[cfg(test)]
+mod fsmonitor_tests {
+    use super::super::model::temp_repo;
+    use super::*;
+
+    // a stand-in for watchman, which reports the paths written to the changes
+    // file once
+    const HOOK: &str = r#"#!/bin/sh
+printf 'token-%s\0' "$(date +%s%N)"
+cat changes 2>/dev/null
+rm -f changes
+"#;
+
+    fn repository(name: &str) -> git2::Repository {
+        let repo = temp_repo::repository(&format!("fsmonitor-{}", name));
+        temp_repo::commit(
+            &repo,
+            &[(".gitignore", "changes\nhook\n*.log\n"), ("a", "a")],
+        );
+        fs::write(repo.workdir().unwrap().join("hook"), HOOK).unwrap();
+        repo
+    }
+
+    fn changes(repo: &git2::Repository, paths: &[&str]) {
+        let content: String = paths.iter().map(|p| format!("{}\0", p)).collect();
+        fs::write(repo.workdir().unwrap().join("changes"), content).unwrap();
+    }
+
+    #[test]
+    fn parse_versions() {
+        let repo = repository("parse");
+        let workdir = repo.workdir().unwrap();
+        changes(&repo, &["a", "b/"]);
+        let (token, paths) = query("sh hook", workdir, 2, "").unwrap();
+        assert!(token.starts_with("token-"), "{}", token);
+        assert_eq!(paths, Changes::Paths(vec!["a".into(), "b/".into()]));
+
+        changes(&repo, &["/"]);
+        assert_eq!(
+            query("sh hook", workdir, 2, &token).unwrap().1,
+            Changes::All
+        );
+        assert!(query("false", workdir, 2, &token).is_err());
+        assert!(query("sh hook", workdir, 3, &token).is_err());
+        fs::remove_dir_all(workdir).unwrap();
+    }
+
+    #[test]
+    fn only_the_reported_paths_are_updated() {
+        let repo = repository("update");
+        let workdir = repo.workdir().unwrap().to_owned();
+        let status = |untracked| local_status(&repo, "sh hook", untracked);
+        assert_eq!(status(Untracked::Normal), LocalStatus::default());
+
+        // not reported, so not noticed
+        fs::write(workdir.join("a"), "changed").unwrap();
+        assert_eq!(status(Untracked::Normal), LocalStatus::default());
+        changes(&repo, &["a"]);
+        assert_eq!(status(Untracked::Normal).unstaged, 1);
+
+        // ignored files do not need a scan
+        fs::write(workdir.join("x.log"), "").unwrap();
+        changes(&repo, &["x.log"]);
+        assert_eq!(status(Untracked::Normal).unstaged, 1);
+
+        // untracked files do
+        fs::write(workdir.join("new"), "").unwrap();
+        changes(&repo, &["new"]);
+        assert_eq!(status(Untracked::Normal).untracked, 1);
+
+        // as do other settings
+        assert_eq!(status(Untracked::None).untracked, 0);
+        fs::remove_dir_all(&workdir).unwrap();
+    }
+
+    #[test]
+    fn updates_count_like_the_scan() {
+        let repo = repository("scan");
+        let workdir = repo.workdir().unwrap().to_owned();
+        temp_repo::commit(&repo, &[("b", "b"), ("c", "c")]);
+        // a staged rename, which the scan counts once
+        fs::rename(workdir.join("c"), workdir.join("d")).unwrap();
+        let mut index = repo.index().unwrap();
+        index.remove_path(Path::new("c")).unwrap();
+        index.add_path(Path::new("d")).unwrap();
+        index.write().unwrap();
+        let status = || local_status(&repo, "sh hook", Untracked::Normal);
+        assert_eq!(status(), model::local_status(&repo, Untracked::Normal));
+
+        fs::write(workdir.join("a"), "changed").unwrap();
+        fs::remove_file(workdir.join("b")).unwrap();
+        fs::write(workdir.join("d"), "changed").unwrap();
+        changes(&repo, &["a", "b", "d"]);
+        let expected = model::local_status(&repo, Untracked::Normal);
+        assert_eq!((expected.staged, expected.unstaged), (1, 3));
+        assert_eq!(status(), expected);
+        fs::remove_dir_all(&workdir).unwrap();
+    }
+}
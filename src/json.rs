@@ -0,0 +1,279 @@
+//! The JSON output, which is meant to be consumed by other tools.  The schema
+//! is described in the README and every breaking change to it has to bump the
+//! version, while new fields may be added without doing so.
+use super::model;
+use super::view::Prompt;
+use serde::Serialize;
+use std::fmt::{self, Display, Formatter};
+
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, Serialize)]
+pub struct Status<'a> {
+    pub version: u32,
+    pub repo: Repo<'a>,
+    pub branch: Option<Branch<'a>>,
+    pub local: Option<Local>,
+    // the segments which did not finish before the timeout, "branch" or
+    // "local"
+    pub timed_out: Vec<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Repo<'a> {
+    pub branch: Option<&'a str>,
+    pub detached: bool,
+    // the full name, e.g. origin/main
+    pub upstream: Option<&'a str>,
+    // in seconds
+    pub last_fetch: Option<u64>,
+    pub state: &'static str,
+    pub progress: Option<Progress>,
+    pub rebase: Option<Rebase<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Rebase<'a> {
+    pub head_name: Option<&'a str>,
+    pub onto: Option<&'a str>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum Progress {
+    Step { step: usize, total: usize },
+    Left { left: usize },
+}
+
+#[derive(Debug, Serialize)]
+pub struct Branch<'a> {
+    pub upstream: &'static str,
+    pub ahead: usize,
+    pub behind: usize,
+    // null when the branch is pushed to its upstream
+    pub push_ahead: Option<usize>,
+    pub default_ahead: usize,
+    pub default_behind: usize,
+    pub default_branch: Option<DefaultBranch<'a>>,
+    // the counts above it only mean that there are more commits
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DefaultBranch<'a> {
+    pub name: &'a str,
+    pub source: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Local {
+    pub staged: usize,
+    pub unmerged: usize,
+    pub unstaged: usize,
+    // null when the untracked files are not counted
+    pub untracked: Option<usize>,
+    pub stashed: usize,
+    // the staged and unstaged files by the kind of the change
+    pub index: model::Changes,
+    pub worktree: Worktree,
+}
+
+/// The unstaged changes, where the new files are the untracked ones.
+#[derive(Debug, Serialize)]
+pub struct Worktree {
+    pub modified: usize,
+    pub deleted: usize,
+    pub renamed: usize,
+    pub typechange: usize,
+}
+
+impl<'a> From<&'a Prompt<'a>> for Status<'a> {
+    fn from(p: &'a Prompt<'a>) -> Status<'a> {
+        Status {
+            version: VERSION,
+            repo: Repo {
+                branch: p.repo.branch.as_deref(),
+                detached: p.repo.detached,
+                upstream: p.repo.upstream.as_deref(),
+                last_fetch: p.repo.last_fetch.map(|d| d.as_secs()),
+                state: state(p.repo.state),
+                progress: p.repo.progress.as_ref().map(|progress| match progress {
+                    model::Progress::Step(step, total) => Progress::Step {
+                        step: *step,
+                        total: *total,
+                    },
+                    model::Progress::Left(left) => Progress::Left { left: *left },
+                }),
+                rebase: p.repo.rebase.as_ref().map(|r| Rebase {
+                    head_name: r.head_name.as_deref(),
+                    onto: r.onto.as_deref(),
+                }),
+            },
+            branch: p.branch.as_ref().map(|b| Branch {
+                upstream: match b.upstream {
+                    model::Upstream::None => "none",
+                    model::Upstream::Gone => "gone",
+                    model::Upstream::InSync => "in-sync",
+                    model::Upstream::Diverged { .. } => "diverged",
+                },
+                ahead: b.upstream.ahead_behind().0,
+                behind: b.upstream.ahead_behind().1,
+                push_ahead: b.push_ahead,
+                default_ahead: b.default_ahead,
+                default_behind: b.default_behind,
+                default_branch: b.default_branch.as_ref().map(|d| DefaultBranch {
+                    name: d.name(),
+                    source: match d {
+                        model::DefaultBranch::Explicit(_) => "explicit",
+                        model::DefaultBranch::RemoteHead(_) => "remote-head",
+                        model::DefaultBranch::InitConfig(_) => "init-config",
+                        model::DefaultBranch::Candidate(_) => "candidate",
+                    },
+                }),
+                limit: b.limit,
+            }),
+            local: p.local.as_ref().map(|l| Local {
+                staged: l.staged,
+                unmerged: l.unmerged,
+                unstaged: l.unstaged,
+                untracked: Some(l.untracked).filter(|_| p.untracked != model::Untracked::None),
+                stashed: l.stashed,
+                index: l.index,
+                worktree: Worktree {
+                    modified: l.worktree.modified,
+                    deleted: l.worktree.deleted,
+                    renamed: l.worktree.renamed,
+                    typechange: l.worktree.typechange,
+                },
+            }),
+            timed_out: [("branch", p.branch_timed_out), ("local", p.local_timed_out)]
+                .iter()
+                .filter(|(_, t)| *t)
+                .map(|(name, _)| *name)
+                .collect(),
+        }
+    }
+}
+
+impl<'a> Display for Status<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let s = serde_json::to_string(self).map_err(|_| fmt::Error)?;
+        write!(f, "{}", s)
+    }
+}
+
+// the names should not change, as the git2 ones might
+fn state(s: git2::RepositoryState) -> &'static str {
+    match s {
+        git2::RepositoryState::Clean => "clean",
+        git2::RepositoryState::Merge => "merge",
+        git2::RepositoryState::Revert => "revert",
+        git2::RepositoryState::RevertSequence => "revert-sequence",
+        git2::RepositoryState::CherryPick => "cherry-pick",
+        git2::RepositoryState::CherryPickSequence => "cherry-pick-sequence",
+        git2::RepositoryState::Bisect => "bisect",
+        git2::RepositoryState::Rebase => "rebase",
+        git2::RepositoryState::RebaseInteractive => "rebase-interactive",
+        git2::RepositoryState::RebaseMerge => "rebase-merge",
+        git2::RepositoryState::ApplyMailbox => "apply-mailbox",
+        git2::RepositoryState::ApplyMailboxOrRebase => "apply-mailbox-or-rebase",
+    }
+}
+
+#[cfg(test)]
+mod json_tests {
+    use super::*;
+    use model::{BranchStatus, LocalStatus, RepoStatus, Upstream};
+
+    fn prompt<'a>() -> Prompt<'a> {
+        Prompt::new(&RepoStatus {
+            branch: Some("feature".into()),
+            detached: false,
+            upstream: None,
+            remote: None,
+            last_fetch: None,
+            state: git2::RepositoryState::RebaseInteractive,
+            progress: None,
+            rebase: None,
+        })
+    }
+
+    #[test]
+    fn repo_only() {
+        assert_eq!(
+            Status::from(&prompt()).to_string(),
+            r#"{"version":1,"repo":{"branch":"feature","detached":false,"upstream":null,"last_fetch":null,"state":"rebase-interactive","progress":null,"rebase":null},"branch":null,"local":null,"timed_out":[]}"#
+        );
+    }
+
+    #[test]
+    fn progress() {
+        let mut p = prompt();
+        p.repo.progress = Some(model::Progress::Step(3, 17));
+        assert!(Status::from(&p)
+            .to_string()
+            .contains(r#""progress":{"step":3,"total":17}"#));
+        p.repo.progress = Some(model::Progress::Left(2));
+        assert!(Status::from(&p)
+            .to_string()
+            .contains(r#""progress":{"left":2}"#));
+    }
+
+    #[test]
+    fn timed_out() {
+        let p = prompt().with_branch_timed_out().with_local_timed_out();
+        assert_eq!(Status::from(&p).timed_out, vec!["branch", "local"]);
+    }
+
+    #[test]
+    fn untracked_not_counted() {
+        let p = prompt()
+            .with_local(Some(LocalStatus::new()))
+            .with_untracked(model::Untracked::None);
+        assert_eq!(Status::from(&p).local.unwrap().untracked, None);
+    }
+
+    #[test]
+    fn everything() {
+        let p = prompt()
+            .with_branch(Some(BranchStatus {
+                upstream: Upstream::Diverged {
+                    ahead: 1,
+                    behind: 2,
+                },
+                push_ahead: Some(5),
+                default_ahead: 3,
+                default_behind: 4,
+                default_branch: Some(model::DefaultBranch::RemoteHead("main".into())),
+                limit: Some(999),
+            }))
+            .with_local(Some(LocalStatus {
+                staged: 1,
+                unmerged: 2,
+                unstaged: 3,
+                untracked: 4,
+                stashed: 5,
+                index: model::Changes {
+                    new: 1,
+                    ..Default::default()
+                },
+                worktree: model::Changes {
+                    modified: 2,
+                    deleted: 1,
+                    ..Default::default()
+                },
+            }));
+        assert_eq!(
+            Status::from(&p).to_string(),
+            concat!(
+                r#"{"version":1,"repo":{"branch":"feature","detached":false,"upstream":null,"last_fetch":null,"state":"rebase-interactive","progress":null,"rebase":null},"#,
+                r#""branch":{"upstream":"diverged","ahead":1,"behind":2,"push_ahead":5,"default_ahead":3,"default_behind":4,"#,
+                r#""default_branch":{"name":"main","source":"remote-head"},"limit":999},"#,
+                r#""local":{"staged":1,"unmerged":2,"unstaged":3,"untracked":4,"stashed":5,"#,
+                r#""index":{"new":1,"modified":0,"deleted":0,"renamed":0,"typechange":0},"#,
+                r#""worktree":{"modified":2,"deleted":1,"renamed":0,"typechange":0}},"#,
+                r#""timed_out":[]}"#
+            )
+        );
+    }
+}
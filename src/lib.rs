@@ -1,62 +1,328 @@
 extern crate clap;
 use clap::Clap;
+use std::env;
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 mod app;
+mod auto_fetch;
+mod cache;
+mod config;
+mod daemon;
 mod examples;
+mod fsmonitor;
+mod json;
 mod model;
 mod parse;
+mod untracked_cache;
 mod view;
 
 pub fn run() -> model::R<()> {
     let opts = app::Opts::parse();
+    let req = daemon::Request::new(&opts)?;
 
-    // convert from the apps params into model;
-    let cs = parse::colors(&opts.colorscheme)?;
-    let bs = parse::bs(&opts.branch_symbols)?;
-    let ss = parse::ss(&opts.status_symbols)?;
+    match &opts.command {
+        Some(app::Command::Daemon { socket }) => {
+            return daemon::socket(socket.as_deref())
+                .and_then(|s| daemon::serve(&s))
+                .or_else(report);
+        }
+        Some(app::Command::Query { socket }) => {
+            let socket = daemon::socket(socket.as_deref()).or_else(report)?;
+            // compute the prompt here when the daemon is not running
+            if let Ok(stream) = daemon::connect(&socket) {
+                return daemon::query(stream, &req, &mut io::stdout());
+            }
+        }
+        None => {}
+    }
 
     if opts.examples {
-        print!("{}", examples::all().with_style(&cs, &bs, &ss));
+        let cfg = settings(&req, Default::default(), Default::default(), None).or_else(report)?;
+        let style = parse::style(&cfg).or_else(report)?;
+        print!(
+            "{}",
+            examples::all()
+                .with_style(&style.colors, &style.branch_symbols, &style.status_symbols)
+                .with_layout(&style.layout)
+        );
         return Ok(());
     }
 
-    let repo = git2::Repository::discover(&opts.path).or_else(|e| Err(format!("{:?}", e)))?;
-    let r = model::repo_status(&repo)?;
-    let prompt = view::Prompt::new(&r).with_style(&cs, &bs, &ss);
+    let repo = git2::Repository::discover(&req.path).map_err(|e| format!("{:?}", e))?;
+    if opts.refresh_cache {
+        return refresh_cache(&req, &repo);
+    }
+    let (no_cache, print_updates) = (opts.no_cache, req.print_updates);
+    let local_status = |r: &git2::Repository, untracked, tx: Sender<Update>| {
+        // in the thread, as even the key takes a while in large worktrees
+        spawn(r, move |r| {
+            let key = Some(r)
+                .filter(|_| !no_cache)
+                .and_then(|r| cache::key(r, untracked).ok());
+            if let Some((status, scanned)) = key.as_ref().and_then(|k| cache::lookup(r, k)) {
+                // the shell would wait for the scan otherwise, and the prompt
+                // exits as soon as the status is sent
+                if !print_updates {
+                    if older(scanned, REFRESH_AFTER) {
+                        refresh_cache_in_background();
+                    }
+                    let _ = tx.send(Update::Cached(status));
+                    return;
+                }
+                let _ = tx.send(Update::Cached(status));
+            }
+            let status = scan(r, untracked, no_cache);
+            if let Some(k) = key {
+                let _ = cache::store(r, &k, &status);
+            }
+            let _ = tx.send(Update::Local(status));
+        })
+    };
+    prompt(&req, &repo, local_status, &mut io::stdout())
+}
+
+/// A segment which finished computing.
+enum Update {
+    Branch(Option<model::BranchStatus>),
+    Local(model::LocalStatus),
+    // which might be outdated, so it is followed by the scanned one when
+    // printing the updates
+    Cached(model::LocalStatus),
+}
 
-    if opts.print_updates {
-        let current = format!("{}", prompt);
-        println!("{}", current);
-        let prompt = prompt.with_branch(
-            r.branch
-                .as_ref()
-                .and_then(|b| model::branch_status(&repo, b, &opts.default_branch).ok()),
-        );
-        let next = format!("{}", prompt);
-        if next != current {
-            let current = next;
-            println!("{}", current);
-        }
-        let next = prompt
-            .with_local(Some(model::local_status(&repo)))
-            .to_string();
-        if next != current {
-            let current = next;
-            println!("{}", current);
+/// Print the prompt for the repository.  The branch and the local status are
+/// computed at the same time, the latter by the given function so that it can
+/// be cached, which sends the result when it is done.  The segments which are
+/// not computed by the deadline are printed as unknown.
+fn prompt<F>(
+    req: &daemon::Request,
+    repo: &git2::Repository,
+    local_status: F,
+    out: &mut dyn Write,
+) -> model::R<()>
+where
+    F: FnOnce(&git2::Repository, model::Untracked, Sender<Update>),
+{
+    let mut println = |s: &str| writeln!(out, "{}", s).map_err(|e| format!("{:?}", e));
+    let cfg = git_settings(req, repo).or_else(report)?;
+    if cfg.disable == Some(true) {
+        return println("");
+    }
+
+    if let Some(interval) = parse::auto_fetch(&cfg) {
+        // a failed fetch only means that the counts are not updated
+        let _ = auto_fetch::start(repo, interval);
+    }
+
+    // convert from the apps params into model;
+    let style = parse::style(&cfg).or_else(report)?;
+    let candidates: Vec<String> = cfg
+        .default_branch_candidates
+        .as_deref()
+        .unwrap_or_default()
+        .split(',')
+        .map(str::to_owned)
+        .collect();
+    let untracked = parse::untracked_mode(&cfg).or_else(report)?;
+    let limit = cfg
+        .ahead_behind_limit
+        .filter(|l| *l > 0)
+        .map(|l| l as usize);
+    let deadline = match cfg.timeout {
+        Some(t) if t > 0 => Some(Instant::now() + Duration::from_millis(t)),
+        _ => None,
+    };
+
+    let describe = parse::setting("describe", &cfg.describe, parse::describe).or_else(report)?;
+    let mut r = model::repo_status(repo, &describe)?;
+    let stale_fetch = parse::stale_fetch(&cfg);
+    // the JSON has it for other tools to decide, while the prompt only shows
+    // it when it is old
+    if req.format == "json" || (stale_fetch.is_some() && style.layout.contains(view::Field::Fetch))
+    {
+        r.last_fetch = model::since_fetch(repo, SystemTime::now());
+    }
+    let (tx, rx) = mpsc::channel();
+    let mut branch_pending = false;
+    if let Some(b) = r.branch.clone() {
+        let (explicit, tx) = (cfg.default_branch.clone(), tx.clone());
+        spawn(repo, move |repo| {
+            let candidates: Vec<&str> = candidates.iter().map(String::as_str).collect();
+            let status = model::branch_status(repo, &b, explicit.as_deref(), &candidates, limit);
+            let _ = tx.send(Update::Branch(status.ok()));
+        });
+        branch_pending = true;
+    }
+    local_status(repo, untracked, tx);
+    let mut local_pending = true;
+
+    let mut prompt = view::Prompt::new(&r)
+        .with_style(&style.colors, &style.branch_symbols, &style.status_symbols)
+        .with_layout(&style.layout)
+        .with_untracked(untracked)
+        .with_upstream_shown(cfg.show_upstream == Some(true))
+        .with_stale_fetch(stale_fetch);
+    let render = |p: &view::Prompt| match req.format.as_str() {
+        "json" => json::Status::from(p).to_string(),
+        _ => p.to_string(),
+    };
+
+    let mut current = render(&prompt);
+    if req.print_updates {
+        println(&current)?;
+    }
+    while branch_pending || local_pending {
+        prompt = match wait(&rx, deadline) {
+            Some(Update::Branch(b)) => {
+                branch_pending = false;
+                prompt.with_branch(b)
+            }
+            Some(Update::Local(l)) => {
+                local_pending = false;
+                prompt.with_local(Some(l))
+            }
+            Some(Update::Cached(l)) => {
+                local_pending = req.print_updates;
+                prompt.with_local(Some(l))
+            }
+            // the deadline has passed or the computations failed
+            None => break,
+        };
+        if req.print_updates {
+            let next = render(&prompt);
+            if next != current {
+                current = next;
+                println(&current)?;
+            }
         }
-    } else {
-        println!(
-            "{}",
-            prompt
-                .with_branch(
-                    r.branch.as_ref().and_then(|b| model::branch_status(
-                        &repo,
-                        b,
-                        &opts.default_branch
-                    )
-                    .ok()),
-                )
-                .with_local(Some(model::local_status(&repo)))
-        );
+    }
+    if branch_pending {
+        prompt = prompt.with_branch_timed_out();
+    }
+    if local_pending && prompt.local.is_none() {
+        prompt = prompt.with_local_timed_out();
+    }
+    let next = render(&prompt);
+    if !req.print_updates || next != current {
+        println(&next)?;
     }
     Ok(())
 }
+
+/// Run the computation in a thread with its own handle of the repository, as
+/// the handles cannot be shared between threads.
+fn spawn<F>(repo: &git2::Repository, f: F)
+where
+    F: FnOnce(&git2::Repository) + Send + 'static,
+{
+    let path = repo.workdir().unwrap_or_else(|| repo.path()).to_owned();
+    thread::spawn(move || {
+        if let Ok(repo) = git2::Repository::open(&path) {
+            f(&repo);
+        }
+    });
+}
+
+/// Wait for the value until the deadline, or forever without one.  libgit2
+/// cannot be interrupted, so the thread is left running after the deadline.
+fn wait<T>(rx: &Receiver<T>, deadline: Option<Instant>) -> Option<T> {
+    match deadline {
+        Some(d) => rx
+            .recv_timeout(d.saturating_duration_since(Instant::now()))
+            .ok(),
+        None => rx.recv().ok(),
+    }
+}
+
+fn git_settings(req: &daemon::Request, repo: &git2::Repository) -> model::R<config::Config> {
+    let cfg = repo.config().map_err(|e| format!("{:?}", e))?;
+    settings(
+        req,
+        config::Config::from_git_levels(&cfg, config::GLOBAL)?,
+        config::Config::from_git_levels(&cfg, config::LOCAL)?,
+        config::show_untracked_files(&cfg)?,
+    )
+}
+
+/// Scan the worktree and store the local status for the next prompt.
+fn refresh_cache(req: &daemon::Request, repo: &git2::Repository) -> model::R<()> {
+    let untracked = parse::untracked_mode(&git_settings(req, repo)?)?;
+    let key = cache::key(repo, untracked)?;
+    cache::store(repo, &key, &scan(repo, untracked, false))
+}
+
+// with the help of the untracked cache or the fsmonitor hook, unless the
+// caches are to be bypassed
+fn scan(
+    repo: &git2::Repository,
+    untracked: model::Untracked,
+    no_cache: bool,
+) -> model::LocalStatus {
+    if no_cache {
+        return model::local_status(repo, untracked);
+    }
+    if untracked_cache::enabled(repo) {
+        match untracked_cache::local_status(repo, untracked) {
+            Ok(status) => return status,
+            // e.g. without git in the PATH, which is needed for the cache
+            Err(e) => eprintln!("git-prompt: {}", e),
+        }
+    }
+    match fsmonitor::hook(repo) {
+        Some(hook) => fsmonitor::local_status(repo, &hook, untracked),
+        None => model::local_status(repo, untracked),
+    }
+}
+
+// the cached status is scanned again at most this often, so that the prompts
+// do not keep scanning a large worktree in the background
+const REFRESH_AFTER: Duration = Duration::from_secs(5);
+
+// a time in the future because of a clock skew is a recent one
+fn older(t: SystemTime, than: Duration) -> bool {
+    SystemTime::now()
+        .duration_since(t)
+        .map(|d| d >= than)
+        .unwrap_or(false)
+}
+
+// in a detached process with the same arguments, as the shell waits for this
+// one to exit
+fn refresh_cache_in_background() {
+    let _ = env::current_exe().and_then(|exe| {
+        Command::new(exe)
+            .arg("--refresh-cache")
+            .args(env::args_os().skip(1))
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+    });
+}
+
+// the command line flags take precedence over the git config of the
+// repository, which takes precedence over the config file, which in turn takes
+// precedence over the global git config
+fn settings(
+    req: &daemon::Request,
+    global: config::Config,
+    local: config::Config,
+    show_untracked_files: Option<String>,
+) -> model::R<config::Config> {
+    let file = config::Config::load(req.config.as_deref())?;
+    let mut cfg = global.merge(file).merge(local).merge(req.flags.clone());
+    if cfg.untracked.is_none() && cfg.show_untracked.is_none() {
+        cfg.untracked = show_untracked_files;
+    }
+    Ok(config::Config::defaults().merge(cfg))
+}
+
+// unlike the repository errors, the configuration errors need to be fixed by
+// the user, so make them visible
+fn report<T>(e: String) -> model::R<T> {
+    eprintln!("git-prompt: {}", e);
+    Err(e)
+}
@@ -1,3 +1,7 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BinaryHeap, HashMap};
+use std::time::{Duration, SystemTime};
+
 type RepoState = git2::RepositoryState;
 pub type R<T> = Result<T, String>;
 
@@ -5,13 +9,130 @@ pub type R<T> = Result<T, String>;
 pub struct RepoStatus {
     // the branch might not be known, when the repo does not have any commits
     pub branch: Option<String>,
+    // HEAD is not a branch, which is then described by a tag or a remote
+    // branch, or else the abbreviated commit id
+    pub detached: bool,
+    // the branch the branch tracks, e.g. origin/main, which might not exist
+    // anymore
+    pub upstream: Option<String>,
+    // the remote of the upstream from branch.<name>.remote, which is . for a
+    // local branch
+    pub remote: Option<String>,
+    // since the remotes were fetched, so the ahead/behind counts might be
+    // out of date
+    pub last_fetch: Option<Duration>,
     pub state: RepoState,
+    // of the rebase, am, cherry-pick or revert in progress
+    pub progress: Option<Progress>,
+    pub rebase: Option<Rebase>,
+}
+
+/// A step of describing a detached HEAD, which are tried in the configured
+/// order before the abbreviated commit id.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Describe {
+    // a tag pointing at the commit
+    Tag,
+    // the nearest tag and the number of commits since, e.g. v1.2.0~3
+    Nearest,
+    // a remote branch which contains the commit, which walks the history of
+    // every remote branch, before the timeout applies
+    Remote,
+}
+
+/// The rebase in progress, during which HEAD is detached.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rebase {
+    // the branch being rebased, unless the rebase started on a detached HEAD
+    pub head_name: Option<String>,
+    // the abbreviated id of the commit the branch is rebased onto
+    pub onto: Option<String>,
+}
+
+/// How far the operation in progress is.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Progress {
+    // the step being applied and the number of steps of a rebase or am
+    Step(usize, usize),
+    // the commits left to cherry-pick or revert, including the current one,
+    // as the sequencer does not keep the ones which are done
+    Left(usize),
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct BranchStatus {
-    pub ahead: usize,
-    pub behind: usize,
+    pub upstream: Upstream,
+    // the commits which are not pushed yet, when the branch is pushed
+    // somewhere else than it is pulled from and was pushed there before
+    pub push_ahead: Option<usize>,
+    // compared to the default branch on the remote
+    pub default_ahead: usize,
+    pub default_behind: usize,
+    // not set when the branch is the default branch itself
+    pub default_branch: Option<DefaultBranch>,
+    // the counts are not walked further than this, so a count above it only
+    // means that there are more commits
+    pub limit: Option<usize>,
+}
+
+/// How the branch compares to the branch it tracks.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Upstream {
+    // branch.<name>.merge is not set
+    #[default]
+    None,
+    // the tracked branch does not exist anymore, e.g. after it was deleted
+    // on the remote and pruned
+    Gone,
+    InSync,
+    // ahead of it, behind it or both
+    Diverged {
+        ahead: usize,
+        behind: usize,
+    },
+}
+
+impl Upstream {
+    pub fn ahead_behind(&self) -> (usize, usize) {
+        match self {
+            Upstream::Diverged { ahead, behind } => (*ahead, *behind),
+            _ => (0, 0),
+        }
+    }
+}
+
+impl From<(usize, usize)> for Upstream {
+    fn from((ahead, behind): (usize, usize)) -> Upstream {
+        match (ahead, behind) {
+            (0, 0) => Upstream::InSync,
+            (ahead, behind) => Upstream::Diverged { ahead, behind },
+        }
+    }
+}
+
+/// The branch the current branch is additionally compared against, tagged
+/// with where it was found.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DefaultBranch {
+    // given on the command line or in the environment
+    Explicit(String),
+    // the branch refs/remotes/<remote>/HEAD points to
+    RemoteHead(String),
+    // the init.defaultBranch setting
+    InitConfig(String),
+    // the first of the configured candidates which exists
+    Candidate(String),
+}
+
+impl DefaultBranch {
+    pub fn name(&self) -> &str {
+        match self {
+            DefaultBranch::Explicit(n)
+            | DefaultBranch::RemoteHead(n)
+            | DefaultBranch::InitConfig(n)
+            | DefaultBranch::Candidate(n) => n,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Default, PartialEq)]
@@ -20,6 +141,32 @@ pub struct LocalStatus {
     pub unmerged: usize,
     pub unstaged: usize,
     pub untracked: usize,
+    pub stashed: usize,
+    // the staged and the unstaged files by the kind of the change, where the
+    // new files in the worktree are the untracked ones instead
+    pub index: Changes,
+    pub worktree: Changes,
+}
+
+/// The changed files by the kind of the change, like the letters of git status
+/// --short.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Changes {
+    pub new: usize,
+    pub modified: usize,
+    pub deleted: usize,
+    pub renamed: usize,
+    pub typechange: usize,
+}
+
+impl Changes {
+    pub const NONE: Changes = Changes {
+        new: 0,
+        modified: 0,
+        deleted: 0,
+        renamed: 0,
+        typechange: 0,
+    };
 }
 
 impl LocalStatus {
@@ -30,6 +177,19 @@ impl LocalStatus {
     }
 }
 
+/// Which untracked files are counted, like --untracked-files of git status.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Untracked {
+    // not looked for at all, which is the fastest
+    None,
+    // the untracked directories are counted once
+    #[default]
+    Normal,
+    // every file in the untracked directories is counted
+    All,
+}
+
 pub trait Repo {
     fn state(&self) -> git2::RepositoryState;
     fn head(&self) -> Result<git2::Reference, git2::Error>;
@@ -37,16 +197,35 @@ pub trait Repo {
         &self,
         options: Option<&mut git2::StatusOptions>,
     ) -> Result<git2::Statuses, git2::Error>;
-    fn find_branch(
-        &self,
-        name: &str,
-        branch_type: git2::BranchType,
-    ) -> Result<git2::Branch, git2::Error>;
-    fn graph_ahead_behind(
+    /// The commits which are only reachable from local and from upstream,
+    /// counted up to one more than the limit.
+    fn ahead_behind(
         &self,
         local: git2::Oid,
         upstream: git2::Oid,
+        limit: Option<usize>,
     ) -> Result<(usize, usize), git2::Error>;
+    fn stash_count(&self) -> Result<usize, git2::Error>;
+    fn refname_to_id(&self, name: &str) -> Result<git2::Oid, git2::Error>;
+    fn symbolic_target(&self, name: &str) -> Option<String>;
+    fn remotes(&self) -> Result<Vec<String>, git2::Error>;
+    fn config_string(&self, name: &str) -> Result<String, git2::Error>;
+    // the full name of the branch the local branch tracks, which might not
+    // exist anymore
+    fn upstream_name(&self, branch: &str) -> Option<String>;
+    // the content of the file in the git directory
+    fn git_file(&self, name: &str) -> Option<String>;
+    // abbreviated like git does, by core.abbrev and long enough to be unique
+    fn short_id(&self, oid: git2::Oid) -> Option<String>;
+    // like git describe --tags --long, which only takes tags pointing at the
+    // commit when exact
+    fn describe(&self, oid: git2::Oid, exact: bool) -> Option<String>;
+    fn remote_branches(&self) -> Vec<(String, git2::Oid)>;
+    fn descendant_of(&self, commit: git2::Oid, ancestor: git2::Oid) -> bool;
+    // when FETCH_HEAD was written, or else the newest entry of the reflogs of
+    // the branches the HEADs of the remotes point to, which are also there
+    // after a clone
+    fn last_fetch(&self) -> Option<SystemTime>;
 }
 
 impl Repo for git2::Repository {
@@ -62,37 +241,455 @@ impl Repo for git2::Repository {
     ) -> Result<git2::Statuses, git2::Error> {
         self.statuses(options)
     }
-    fn find_branch(
-        &self,
-        name: &str,
-        branch_type: git2::BranchType,
-    ) -> Result<git2::Branch, git2::Error> {
-        self.find_branch(name, branch_type)
-    }
-    fn graph_ahead_behind(
+    fn ahead_behind(
         &self,
         local: git2::Oid,
         upstream: git2::Oid,
+        limit: Option<usize>,
     ) -> Result<(usize, usize), git2::Error> {
-        self.graph_ahead_behind(local, upstream)
+        match limit {
+            Some(limit) => bounded_ahead_behind(self, local, upstream, limit),
+            None => self.graph_ahead_behind(local, upstream),
+        }
+    }
+    fn stash_count(&self) -> Result<usize, git2::Error> {
+        // every stash entry is a reflog entry of refs/stash, which unlike
+        // stash_foreach does not need a mutable repository
+        self.reflog("refs/stash").map(|r| r.len())
+    }
+    fn refname_to_id(&self, name: &str) -> Result<git2::Oid, git2::Error> {
+        self.refname_to_id(name)
+    }
+    fn symbolic_target(&self, name: &str) -> Option<String> {
+        self.find_reference(name)
+            .ok()?
+            .symbolic_target()
+            .map(|t| t.to_owned())
+    }
+    fn remotes(&self) -> Result<Vec<String>, git2::Error> {
+        Ok(self
+            .remotes()?
+            .iter()
+            .flatten()
+            .map(|r| r.to_owned())
+            .collect())
+    }
+    fn config_string(&self, name: &str) -> Result<String, git2::Error> {
+        self.config()?.get_string(name)
+    }
+    fn upstream_name(&self, branch: &str) -> Option<String> {
+        self.branch_upstream_name(&format!("refs/heads/{}", branch))
+            .ok()?
+            .as_str()
+            .map(str::to_owned)
+    }
+    fn git_file(&self, name: &str) -> Option<String> {
+        std::fs::read_to_string(self.path().join(name)).ok()
+    }
+    fn describe(&self, oid: git2::Oid, exact: bool) -> Option<String> {
+        let mut options = git2::DescribeOptions::new();
+        options.describe_tags();
+        if exact {
+            options.max_candidates_tags(0);
+        }
+        self.find_object(oid, None)
+            .ok()?
+            .describe(&options)
+            .ok()?
+            .format(Some(
+                git2::DescribeFormatOptions::new().always_use_long_format(true),
+            ))
+            .ok()
+    }
+    fn remote_branches(&self) -> Vec<(String, git2::Oid)> {
+        self.branches(Some(git2::BranchType::Remote))
+            .map(|branches| {
+                branches
+                    .flatten()
+                    .filter_map(|(b, _)| Some((b.name().ok()??.to_owned(), b.get().target()?)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+    fn descendant_of(&self, commit: git2::Oid, ancestor: git2::Oid) -> bool {
+        self.graph_descendant_of(commit, ancestor).unwrap_or(false)
+    }
+    fn short_id(&self, oid: git2::Oid) -> Option<String> {
+        let id = self.find_object(oid, None).ok()?.short_id().ok()?;
+        id.as_str().map(str::to_owned)
+    }
+    fn last_fetch(&self) -> Option<SystemTime> {
+        if let Ok(t) = std::fs::metadata(self.path().join("FETCH_HEAD")).and_then(|m| m.modified())
+        {
+            return Some(t);
+        }
+        // rather than the reflogs of all of the remote branches, of which
+        // there might be thousands
+        Repo::remotes(self)
+            .ok()?
+            .iter()
+            .filter_map(|remote| {
+                let head = self.find_reference(&format!("refs/remotes/{}/HEAD", remote));
+                let branch = head.ok()?.symbolic_target()?.to_owned();
+                let reflog = self.reflog(&branch).ok()?;
+                let newest = reflog.get(0)?.committer().when().seconds();
+                Some(SystemTime::UNIX_EPOCH + Duration::from_secs(newest.max(0) as u64))
+            })
+            .max()
+    }
+}
+
+const LOCAL: u8 = 1;
+const UPSTREAM: u8 = 2;
+const BOTH: u8 = LOCAL | UPSTREAM;
+const WALKED: u8 = 4;
+
+/// Walk the commits from the newest, like graph_ahead_behind does, until only
+/// the ones reachable from both are left or either count is over the limit.
+/// The other count is then only a lower bound, the ones found so far, as
+/// telling it exactly would need the walk down to the merge base.  Like
+/// graph_ahead_behind, the counts can be off when the commit dates are skewed.
+pub fn bounded_ahead_behind(
+    repo: &git2::Repository,
+    local: git2::Oid,
+    upstream: git2::Oid,
+    limit: usize,
+) -> Result<(usize, usize), git2::Error> {
+    let mut flags: HashMap<git2::Oid, u8> = HashMap::new();
+    *flags.entry(local).or_default() |= LOCAL;
+    *flags.entry(upstream).or_default() |= UPSTREAM;
+    let mut queue = BinaryHeap::new();
+    // the queued commits which are not reachable from both
+    let mut exclusive = 0;
+    for (oid, f) in &flags {
+        queue.push((repo.find_commit(*oid)?.time().seconds(), *oid));
+        if *f != BOTH {
+            exclusive += 1;
+        }
     }
+
+    let (mut ahead, mut behind) = (0, 0);
+    while exclusive > 0 && ahead <= limit && behind <= limit {
+        let oid = match queue.pop() {
+            Some((_, oid)) => oid,
+            None => break,
+        };
+        let f = flags[&oid];
+        flags.insert(oid, f | WALKED);
+        match f {
+            LOCAL => ahead += 1,
+            UPSTREAM => behind += 1,
+            _ => {}
+        }
+        if f != BOTH {
+            exclusive -= 1;
+        }
+        for parent in repo.find_commit(oid)?.parents() {
+            match flags.get(&parent.id()).copied() {
+                None => {
+                    flags.insert(parent.id(), f);
+                    queue.push((parent.time().seconds(), parent.id()));
+                    if f != BOTH {
+                        exclusive += 1;
+                    }
+                }
+                // walked already, which only happens with skewed dates
+                Some(p) if p & WALKED != 0 => {}
+                Some(p) => {
+                    flags.insert(parent.id(), p | f);
+                    if p != BOTH && p | f == BOTH {
+                        exclusive -= 1;
+                    }
+                }
+            }
+        }
+    }
+    Ok((ahead, behind))
 }
 
-pub fn repo_status(repo: &dyn Repo) -> R<RepoStatus> {
+#[cfg(test)]
+#[allow(dead_code)]
+mod ahead_behind {
+    use super::temp_repo;
+    use super::*;
+    use std::fs;
+
+    // the commits are a second apart, as the walk goes by their dates
+    fn commit(repo: &git2::Repository, parents: &[git2::Oid], time: i64) -> git2::Oid {
+        let sig =
+            git2::Signature::new("test", "test@example.com", &git2::Time::new(time, 0)).unwrap();
+        let tree = repo
+            .find_tree(repo.index().unwrap().write_tree().unwrap())
+            .unwrap();
+        let parents: Vec<git2::Commit> = parents
+            .iter()
+            .map(|p| repo.find_commit(*p).unwrap())
+            .collect();
+        let parents: Vec<&git2::Commit> = parents.iter().collect();
+        repo.commit(None, &sig, &sig, "c", &tree, &parents).unwrap()
+    }
+
+    fn chain(repo: &git2::Repository, from: git2::Oid, n: usize, time: i64) -> git2::Oid {
+        (0..n).fold(from, |c, i| commit(repo, &[c], time + i as i64))
+    }
+
+    #[test]
+    fn same_as_the_full_walk() {
+        let dir = temp_repo::dir("ahead-behind");
+        let repo = git2::Repository::init(&dir).unwrap();
+        let base = chain(&repo, commit(&repo, &[], 0), 10, 1);
+        let local = chain(&repo, base, 5, 100);
+        let upstream = chain(&repo, base, 3, 200);
+        // merging the upstream makes its commits reachable from both
+        let merged = commit(&repo, &[local, upstream], 300);
+        let newer = chain(&repo, upstream, 2, 400);
+
+        for (l, u) in &[
+            (local, upstream),
+            (merged, newer),
+            (base, local),
+            (local, local),
+        ] {
+            assert_eq!(
+                bounded_ahead_behind(&repo, *l, *u, 100).unwrap(),
+                repo.graph_ahead_behind(*l, *u).unwrap()
+            );
+        }
+        // the newer upstream commits are walked first, so the walk stops when
+        // they are over the limit and the 5 commits ahead are not all found
+        let (ahead, behind) = bounded_ahead_behind(&repo, local, upstream, 2).unwrap();
+        assert!(behind > 2, "{}", behind);
+        assert!(ahead < 5, "{}", ahead);
+        // which is not the case when the limit is not reached on either side
+        assert_eq!(
+            bounded_ahead_behind(&repo, local, upstream, 5).unwrap(),
+            (5, 3)
+        );
+        assert_eq!(bounded_ahead_behind(&repo, base, local, 1).unwrap(), (0, 2));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+pub fn repo_status(repo: &dyn Repo, describe: &[Describe]) -> R<RepoStatus> {
+    let head = repo.head().map_err(|e| format!("{:?}", e))?;
+    let (branch, detached) = get_repo_rev(repo, &head, describe);
+    let upstream = branch
+        .as_deref()
+        .filter(|_| !detached)
+        .and_then(|b| tracked(repo, b));
+    let remote = branch
+        .as_deref()
+        .filter(|_| upstream.is_some())
+        .and_then(|b| repo.config_string(&format!("branch.{}.remote", b)).ok());
     Ok(RepoStatus {
-        branch: repo
-            .head()
-            .or_else(|e| Err(format!("{:?}", e)))
-            .map(|r| get_repo_rev(&r))?,
+        branch,
+        detached,
+        upstream,
+        remote,
+        // which is only looked up when it is shown, see since_fetch
+        last_fetch: None,
         state: repo.state(),
+        progress: progress(repo),
+        rebase: rebase(repo),
     })
 }
 
-fn get_repo_rev(r: &dyn Reference) -> Option<String> {
+/// The time since the remotes were fetched, if they ever were.
+pub fn since_fetch(repo: &dyn Repo, now: SystemTime) -> Option<Duration> {
+    // a fetch in the future because of a clock skew is a fresh one
+    repo.last_fetch()
+        .map(|t| now.duration_since(t).unwrap_or_default())
+}
+
+// the short name of the upstream, e.g. origin/main, or just the name of a
+// local branch
+fn tracked(repo: &dyn Repo, branch: &str) -> Option<String> {
+    let u = repo.upstream_name(branch)?;
+    u.strip_prefix("refs/remotes/")
+        .or_else(|| u.strip_prefix("refs/heads/"))
+        .map(str::to_owned)
+}
+
+// from the state directory of the merge and the apply backends, where git am
+// does not write these files
+fn rebase(repo: &dyn Repo) -> Option<Rebase> {
+    ["rebase-merge", "rebase-apply"].iter().find_map(|dir| {
+        let file = |name| {
+            repo.git_file(&format!("{}/{}", dir, name))
+                .map(|s| s.trim().to_owned())
+        };
+        let (head_name, onto) = (file("head-name"), file("onto"));
+        if head_name.is_none() && onto.is_none() {
+            return None;
+        }
+        Some(Rebase {
+            head_name: head_name.and_then(|h| h.strip_prefix("refs/heads/").map(str::to_owned)),
+            onto: onto.map(|o| {
+                git2::Oid::from_str(&o)
+                    .ok()
+                    .and_then(|oid| repo.short_id(oid))
+                    .unwrap_or(o)
+            }),
+        })
+    })
+}
+
+/// Read the progress from the same files the prompt of git does, and the
+/// sequencer todo list, which git status reads.
+fn progress(repo: &dyn Repo) -> Option<Progress> {
+    let number = |name: &str| {
+        repo.git_file(name)
+            .and_then(|s| s.trim().parse::<usize>().ok())
+    };
+    let step = |step, total| Some(Progress::Step(number(step)?, number(total)?));
+    step("rebase-merge/msgnum", "rebase-merge/end")
+        .or_else(|| step("rebase-apply/next", "rebase-apply/last"))
+        .or_else(|| {
+            let todo = repo.git_file("sequencer/todo")?;
+            let left = todo
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .count();
+            Some(Progress::Left(left)).filter(|_| left > 0)
+        })
+}
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod progress {
+    use super::temp_repo;
+    use super::test_repo::TestRepo;
+    use super::*;
+    use std::fs;
+
+    fn given(files: &[(&'static str, &'static str)]) -> Option<Progress> {
+        progress(&TestRepo {
+            files: files.iter().cloned().collect(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn nothing_in_progress() {
+        assert_eq!(given(&[]), None);
+    }
+
+    #[test]
+    fn rebase_merge() {
+        let files = [("rebase-merge/msgnum", "3\n"), ("rebase-merge/end", "17\n")];
+        assert_eq!(given(&files), Some(Progress::Step(3, 17)));
+    }
+
+    #[test]
+    fn rebase_apply() {
+        let files = [("rebase-apply/next", "1\n"), ("rebase-apply/last", "4\n")];
+        assert_eq!(given(&files), Some(Progress::Step(1, 4)));
+        // before the first patch is applied
+        assert_eq!(given(&files[..1]), None);
+    }
+
+    #[test]
+    fn rebase_state() {
+        let r = TestRepo {
+            files: vec![
+                ("rebase-merge/head-name", "refs/heads/feature-x\n"),
+                (
+                    "rebase-merge/onto",
+                    "16fa43d098d730aa8dd1618adcfdb55f8d331742\n",
+                ),
+            ]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        };
+        assert_eq!(
+            rebase(&r),
+            Some(Rebase {
+                head_name: Some("feature-x".into()),
+                onto: Some("16fa43d".into()),
+            })
+        );
+
+        let detached = TestRepo {
+            files: vec![("rebase-apply/head-name", "detached HEAD\n")]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+        assert_eq!(
+            rebase(&detached),
+            Some(Rebase {
+                head_name: None,
+                onto: None,
+            })
+        );
+        assert_eq!(rebase(&TestRepo::default()), None);
+    }
+
+    #[test]
+    fn onto_is_abbreviated_like_git() {
+        let repo = temp_repo::repository("onto");
+        repo.config().unwrap().set_i32("core.abbrev", 12).unwrap();
+        let head = repo.refname_to_id("HEAD").unwrap();
+        fs::create_dir(repo.path().join("rebase-merge")).unwrap();
+        fs::write(repo.path().join("rebase-merge/onto"), format!("{}\n", head)).unwrap();
+        let onto = rebase(&repo).and_then(|r| r.onto);
+        assert_eq!(onto, Some(head.to_string()[..12].to_owned()));
+        fs::remove_dir_all(repo.workdir().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn sequencer() {
+        let todo = "pick a2c1fb6 s2\npick 5e48f8c s3\n# comment\n\n";
+        assert_eq!(given(&[("sequencer/todo", todo)]), Some(Progress::Left(2)));
+        assert_eq!(given(&[("sequencer/todo", "")]), None);
+    }
+}
+
+// the branch, or the description of the detached HEAD
+fn get_repo_rev(
+    repo: &dyn Repo,
+    r: &dyn Reference,
+    describe: &[Describe],
+) -> (Option<String>, bool) {
     match r.shorthand() {
-        Some("HEAD") => r.short_id().ok(), // TODO don't discard error
-        Some(b) => Some(b.into()),
-        None => None,
+        Some("HEAD") => {
+            let described = r
+                .target()
+                .and_then(|oid| describe_commit(repo, oid, describe));
+            (described.or_else(|| r.short_id().ok()), true) // TODO don't discard error
+        }
+        Some(b) => (Some(b.into()), false),
+        None => (None, false),
+    }
+}
+
+/// Describe the commit with the first of the steps which works.
+pub fn describe_commit(repo: &dyn Repo, oid: git2::Oid, steps: &[Describe]) -> Option<String> {
+    steps.iter().find_map(|step| match step {
+        Describe::Tag => repo.describe(oid, true).map(|d| nearest_tag(&d)),
+        Describe::Nearest => repo.describe(oid, false).map(|d| nearest_tag(&d)),
+        Describe::Remote => {
+            let mut branches = repo.remote_branches();
+            // prefer origin, like for the default branch
+            branches.sort_by_key(|(name, _)| !name.starts_with("origin/"));
+            branches
+                .into_iter()
+                .find(|(_, tip)| *tip == oid || repo.descendant_of(*tip, oid))
+                .map(|(name, _)| name)
+        }
+    })
+}
+
+// v1.2.0-3-gea02629 as v1.2.0~3, and just the tag when it points at the commit
+fn nearest_tag(described: &str) -> String {
+    let mut parts = described.rsplitn(3, '-');
+    let (_, n, tag) = (parts.next(), parts.next(), parts.next());
+    match (tag, n.and_then(|n| n.parse::<usize>().ok())) {
+        (Some(tag), Some(0)) => tag.to_owned(),
+        (Some(tag), Some(n)) => format!("{}~{}", tag, n),
+        _ => described.to_owned(),
     }
 }
 
@@ -124,6 +721,7 @@ impl<'repo> Reference for git2::Reference<'repo> {
 #[cfg(test)]
 #[allow(dead_code)]
 mod repo_status {
+    use super::test_repo::TestRepo;
     use super::*;
 
     struct TestReference<'a> {
@@ -152,7 +750,94 @@ mod repo_status {
             target: None,
         };
 
-        assert_eq!(get_repo_rev(&r), Some("foo".into()));
+        assert_eq!(
+            get_repo_rev(&TestRepo::default(), &r, ALL),
+            (Some("foo".into()), false)
+        );
+    }
+
+    const ALL: &[Describe] = &[Describe::Tag, Describe::Nearest, Describe::Remote];
+
+    #[test]
+    fn since_last_fetch() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(10_000);
+        let r = |fetched: u64| TestRepo {
+            fetched: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(fetched)),
+            ..Default::default()
+        };
+        assert_eq!(
+            since_fetch(&r(9_000), now),
+            Some(Duration::from_secs(1_000))
+        );
+        assert_eq!(since_fetch(&r(11_000), now), Some(Duration::from_secs(0)));
+        assert_eq!(since_fetch(&TestRepo::default(), now), None);
+    }
+
+    #[test]
+    fn last_fetch_from_the_remote_head() {
+        let repo = temp_repo::repository("last-fetch");
+        repo.remote("origin", "https://example.com/repo.git")
+            .unwrap();
+        let head = repo.refname_to_id("HEAD").unwrap();
+        repo.reference("refs/remotes/origin/main", head, false, "fetch")
+            .unwrap();
+        assert_eq!(Repo::last_fetch(&repo), None);
+
+        repo.reference_symbolic(
+            "refs/remotes/origin/HEAD",
+            "refs/remotes/origin/main",
+            false,
+            "clone",
+        )
+        .unwrap();
+        assert!(Repo::last_fetch(&repo).is_some());
+        std::fs::remove_dir_all(repo.workdir().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn tracked_branch() {
+        let r = TestRepo {
+            config: vec![
+                ("branch.fix.remote", "origin"),
+                ("branch.fix.merge", "refs/heads/bugfix-1234"),
+            ]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        };
+        assert_eq!(tracked(&r, "fix"), Some("origin/bugfix-1234".into()));
+        assert_eq!(tracked(&r, "main"), None);
+    }
+
+    #[test]
+    fn remote_of_the_upstream() {
+        let repo = temp_repo::repository("remote-of-upstream");
+        repo.remote("origin", "https://example.com/repo.git")
+            .unwrap();
+        let branch = repo.head().unwrap().shorthand().unwrap().to_owned();
+        let mut config = repo.config().unwrap();
+        config
+            .set_str(&format!("branch.{}.remote", branch), "origin")
+            .unwrap();
+        config
+            .set_str(
+                &format!("branch.{}.merge", branch),
+                &format!("refs/heads/a/{}", branch),
+            )
+            .unwrap();
+
+        let r = repo_status(&repo, &[]).unwrap();
+        assert_eq!(r.upstream, Some(format!("origin/a/{}", branch)));
+        assert_eq!(r.remote.as_deref(), Some("origin"));
+        std::fs::remove_dir_all(repo.workdir().unwrap()).unwrap();
+    }
+
+    fn detached() -> TestReference<'static> {
+        TestReference {
+            shorthand: Some("HEAD"),
+            short_id: Some("ea02629"),
+            target: Some(git2::Oid::zero()),
+        }
     }
 
     #[test]
@@ -163,34 +848,632 @@ mod repo_status {
             target: git2::Oid::from_str("ea026298c4856b690bc338e917235059fb1fe22a").ok(),
         };
 
-        assert_eq!(get_repo_rev(&r), Some("ea02629".into()));
+        assert_eq!(
+            get_repo_rev(&TestRepo::default(), &r, ALL),
+            (Some("ea02629".into()), true)
+        );
+    }
+
+    #[test]
+    fn exact_tag() {
+        let r = TestRepo {
+            described: Some("v1.2.0-0-gea02629"),
+            ..Default::default()
+        };
+        assert_eq!(
+            get_repo_rev(&r, &detached(), ALL),
+            (Some("v1.2.0".into()), true)
+        );
+    }
+
+    #[test]
+    fn nearest_tag() {
+        let r = TestRepo {
+            described: Some("release-1.2-3-gea02629"),
+            refs: vec!["refs/remotes/origin/main"],
+            ..Default::default()
+        };
+        assert_eq!(
+            get_repo_rev(&r, &detached(), ALL),
+            (Some("release-1.2~3".into()), true)
+        );
+        // the steps are tried in the given order
+        assert_eq!(
+            get_repo_rev(&r, &detached(), &[Describe::Tag, Describe::Remote]),
+            (Some("origin/main".into()), true)
+        );
+        assert_eq!(
+            get_repo_rev(&r, &detached(), &[]),
+            (Some("ea02629".into()), true)
+        );
+    }
+
+    #[test]
+    fn remote_branch() {
+        let r = TestRepo {
+            refs: vec!["refs/remotes/upstream/main", "refs/remotes/origin/main"],
+            ..Default::default()
+        };
+        assert_eq!(
+            get_repo_rev(&r, &detached(), ALL),
+            (Some("origin/main".into()), true)
+        );
     }
 }
 
-pub fn branch_status(repo: &dyn Repo, name: &str, default: &str) -> R<BranchStatus> {
-    let (ahead, behind) = repo
-        .graph_ahead_behind(
-            repo.head()
-                .or_else(|e| Err(format!("{:?}", e)))?
-                .target()
-                .ok_or_else(|| "Failed to get target".to_owned())?,
-            get_remote_ref(repo, name).or_else(|_| get_remote_ref(repo, default))?,
-        )
-        .or_else(|e| Err(format!("{:?}", e)))?;
-    Ok(BranchStatus { ahead, behind })
+pub fn branch_status(
+    repo: &dyn Repo,
+    name: &str,
+    explicit: Option<&str>,
+    candidates: &[&str],
+    limit: Option<usize>,
+) -> R<BranchStatus> {
+    let head = repo.refname_to_id("HEAD").map_err(|e| format!("{:?}", e))?;
+
+    // a failed walk is not the same as no upstream, even if the default branch
+    // could be compared
+    let upstream = upstream(repo, name, head, limit)?;
+    // the commits ahead of the upstream are the unpushed ones otherwise
+    let push_ahead = push_target(repo, name)
+        .filter(|p| *p != repo.upstream_name(name).unwrap_or_default())
+        .and_then(|p| repo.refname_to_id(&p).ok())
+        .and_then(|push| repo.ahead_behind(head, push, limit).ok())
+        .and_then(|(ahead, behind)| match limit {
+            // the commits ahead are not all found then, and without the
+            // count behind nothing would tell
+            Some(limit) if behind > limit && ahead <= limit => None,
+            _ => Some(ahead),
+        });
+    // comparing the default branch with itself would repeat the upstream
+    let default = default_branch(repo, explicit, candidates)
+        .ok()
+        .filter(|d| d.name() != name);
+    let against_default = default.and_then(|d| {
+        get_remote_tracking_ref(repo, d.name())
+            .or_else(|_| {
+                repo.refname_to_id(&format!("refs/heads/{}", d.name()))
+                    .map_err(|e| format!("{:?}", e))
+            })
+            .and_then(|remote| {
+                repo.ahead_behind(head, remote, limit)
+                    .map_err(|e| format!("{:?}", e))
+            })
+            .ok()
+            .map(|counts| (d, counts))
+    });
+
+    let (default_branch, (default_ahead, default_behind)) = match against_default {
+        Some((d, counts)) => (Some(d), counts),
+        None => (None, (0, 0)),
+    };
+    Ok(BranchStatus {
+        upstream,
+        push_ahead,
+        default_ahead,
+        default_behind,
+        default_branch,
+        limit,
+    })
+}
+
+/// Find the default branch of the repository: the explicitly given one, then
+/// the branch the remote HEAD points to, then init.defaultBranch and then the
+/// first of the candidates which exists locally or on a remote.
+pub fn default_branch(
+    repo: &dyn Repo,
+    explicit: Option<&str>,
+    candidates: &[&str],
+) -> R<DefaultBranch> {
+    if let Some(name) = explicit {
+        return Ok(DefaultBranch::Explicit(name.to_owned()));
+    }
+
+    let mut remotes = repo.remotes().unwrap_or_default();
+    // prefer origin, if there are several remotes
+    remotes.sort_by_key(|r| r != "origin");
+
+    for remote in &remotes {
+        let prefix = format!("refs/remotes/{}/", remote);
+        let target = repo.symbolic_target(&format!("{}HEAD", prefix));
+        if let Some(name) = target.as_ref().and_then(|t| t.strip_prefix(&prefix)) {
+            return Ok(DefaultBranch::RemoteHead(name.to_owned()));
+        }
+    }
+
+    let exists = |name: &str| {
+        repo.refname_to_id(&format!("refs/heads/{}", name)).is_ok()
+            || remotes.iter().any(|r| {
+                repo.refname_to_id(&format!("refs/remotes/{}/{}", r, name))
+                    .is_ok()
+            })
+    };
+    if let Ok(name) = repo.config_string("init.defaultBranch") {
+        if exists(&name) {
+            return Ok(DefaultBranch::InitConfig(name));
+        }
+    }
+    candidates
+        .iter()
+        .find(|c| exists(c))
+        .map(|c| DefaultBranch::Candidate((*c).to_owned()))
+        .ok_or_else(|| "failed to find the default branch".to_owned())
+}
+
+fn get_remote_tracking_ref(repo: &dyn Repo, name: &str) -> R<git2::Oid> {
+    let mut remotes = repo.remotes().map_err(|e| format!("{:?}", e))?;
+    remotes.sort_by_key(|r| r != "origin");
+    remotes
+        .iter()
+        .find_map(|r| {
+            repo.refname_to_id(&format!("refs/remotes/{}/{}", r, name))
+                .ok()
+        })
+        .ok_or_else(|| format!("failed to find a remote branch for {}", name))
+}
+
+// a repository for the tests, which only knows the given references, config
+// and files
+#[cfg(test)]
+#[allow(dead_code)]
+mod test_repo {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[derive(Default)]
+    pub struct TestRepo<'a> {
+        pub refs: Vec<&'a str>,
+        pub symbolic: HashMap<&'a str, &'a str>,
+        pub remotes: Vec<&'a str>,
+        pub config: HashMap<&'a str, &'a str>,
+        // in the git directory
+        pub files: HashMap<&'a str, &'a str>,
+        // the output of describe for every commit
+        pub described: Option<&'a str>,
+        // the counts between any two commits
+        pub ahead_behind: Option<(usize, usize)>,
+        pub fetched: Option<SystemTime>,
+    }
+
+    fn unsupported<T>() -> Result<T, git2::Error> {
+        Err(git2::Error::from_str("not supported in tests"))
+    }
+
+    impl<'a> Repo for TestRepo<'a> {
+        fn state(&self) -> git2::RepositoryState {
+            git2::RepositoryState::Clean
+        }
+        fn head(&self) -> Result<git2::Reference, git2::Error> {
+            unsupported()
+        }
+        fn statuses(
+            &self,
+            _options: Option<&mut git2::StatusOptions>,
+        ) -> Result<git2::Statuses, git2::Error> {
+            unsupported()
+        }
+        fn ahead_behind(
+            &self,
+            _local: git2::Oid,
+            _upstream: git2::Oid,
+            _limit: Option<usize>,
+        ) -> Result<(usize, usize), git2::Error> {
+            self.ahead_behind.map_or_else(unsupported, Ok)
+        }
+        fn stash_count(&self) -> Result<usize, git2::Error> {
+            Ok(0)
+        }
+        fn refname_to_id(&self, name: &str) -> Result<git2::Oid, git2::Error> {
+            if self.refs.contains(&name) {
+                Ok(git2::Oid::zero())
+            } else {
+                unsupported()
+            }
+        }
+        fn symbolic_target(&self, name: &str) -> Option<String> {
+            self.symbolic.get(name).map(|t| (*t).to_owned())
+        }
+        fn remotes(&self) -> Result<Vec<String>, git2::Error> {
+            Ok(self.remotes.iter().map(|r| (*r).to_owned()).collect())
+        }
+        fn config_string(&self, name: &str) -> Result<String, git2::Error> {
+            self.config
+                .get(name)
+                .map(|v| (*v).to_owned())
+                .ok_or_else(|| git2::Error::from_str("missing"))
+        }
+        // without applying the fetch refspec of the remote
+        fn upstream_name(&self, branch: &str) -> Option<String> {
+            let remote = self
+                .config
+                .get(format!("branch.{}.remote", branch).as_str())?;
+            let merge = self
+                .config
+                .get(format!("branch.{}.merge", branch).as_str())?;
+            Some(format!(
+                "refs/remotes/{}/{}",
+                remote,
+                merge.strip_prefix("refs/heads/")?
+            ))
+        }
+        fn git_file(&self, name: &str) -> Option<String> {
+            self.files.get(name).map(|c| (*c).to_owned())
+        }
+        // without any objects, so the default of core.abbrev is unique
+        fn short_id(&self, oid: git2::Oid) -> Option<String> {
+            Some(oid.to_string()[..7].to_owned())
+        }
+        fn describe(&self, _oid: git2::Oid, exact: bool) -> Option<String> {
+            self.described
+                .filter(|d| !exact || d.contains("-0-g"))
+                .map(str::to_owned)
+        }
+        // every reference points at the zero id
+        fn remote_branches(&self) -> Vec<(String, git2::Oid)> {
+            self.refs
+                .iter()
+                .filter_map(|r| r.strip_prefix("refs/remotes/"))
+                .map(|r| (r.to_owned(), git2::Oid::zero()))
+                .collect()
+        }
+        fn descendant_of(&self, _commit: git2::Oid, _ancestor: git2::Oid) -> bool {
+            false
+        }
+        fn last_fetch(&self) -> Option<SystemTime> {
+            self.fetched
+        }
+    }
 }
 
-fn get_remote_ref(repo: &dyn Repo, name: &str) -> R<git2::Oid> {
-    repo.find_branch(name, git2::BranchType::Local)
-        .or_else(|e| Err(format!("{:?}", e)))?
-        .upstream()
-        .or_else(|e| Err(format!("{:?}", e)))?
-        .get()
-        .target()
-        .ok_or_else(|| "failed to get remote branch name".to_owned())
+// real repositories in the temporary directory, for the tests which need git
+// itself or the files of the git directory
+#[cfg(test)]
+#[allow(dead_code)]
+pub mod temp_repo {
+    use std::env;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::process;
+
+    /// An empty directory, which is unique to the test of the given name.
+    pub fn dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("git-prompt-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// A repository with an empty commit, so that HEAD is not unborn.
+    pub fn repository(name: &str) -> git2::Repository {
+        let repo = git2::Repository::init(dir(name)).unwrap();
+        commit(&repo, &[]);
+        repo
+    }
+
+    /// Write the files and commit them on top of HEAD.
+    pub fn commit(repo: &git2::Repository, files: &[(&str, &str)]) -> git2::Oid {
+        let workdir = repo.workdir().unwrap();
+        let mut index = repo.index().unwrap();
+        for (path, content) in files {
+            fs::write(workdir.join(path), content).unwrap();
+            index.add_path(Path::new(path)).unwrap();
+        }
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = git2::Signature::now("test", "test@example.com").unwrap();
+        let parent = repo.head().ok().and_then(|h| h.peel_to_commit().ok());
+        let parents: Vec<&git2::Commit> = parent.iter().collect();
+        repo.commit(Some("HEAD"), &sig, &sig, "commit", &tree, &parents)
+            .unwrap()
+    }
 }
 
-pub fn local_status(repo: &dyn Repo) -> LocalStatus {
+#[cfg(test)]
+#[allow(dead_code)]
+mod default_branch {
+    use super::test_repo::TestRepo;
+    use super::*;
+
+    const CANDIDATES: &[&str] = &["main", "master", "trunk"];
+
+    #[test]
+    fn explicit() {
+        let r = TestRepo::default();
+        assert_eq!(
+            default_branch(&r, Some("dev"), CANDIDATES),
+            Ok(DefaultBranch::Explicit("dev".into()))
+        );
+    }
+
+    #[test]
+    fn remote_head_prefers_origin() {
+        let r = TestRepo {
+            remotes: vec!["fork", "origin"],
+            symbolic: vec![
+                ("refs/remotes/fork/HEAD", "refs/remotes/fork/develop"),
+                ("refs/remotes/origin/HEAD", "refs/remotes/origin/trunk"),
+            ]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        };
+        assert_eq!(
+            default_branch(&r, None, CANDIDATES),
+            Ok(DefaultBranch::RemoteHead("trunk".into()))
+        );
+    }
+
+    #[test]
+    fn init_config() {
+        let r = TestRepo {
+            refs: vec!["refs/heads/master", "refs/heads/stable"],
+            config: vec![("init.defaultBranch", "stable")].into_iter().collect(),
+            ..Default::default()
+        };
+        assert_eq!(
+            default_branch(&r, None, CANDIDATES),
+            Ok(DefaultBranch::InitConfig("stable".into()))
+        );
+    }
+
+    #[test]
+    fn init_config_must_exist() {
+        let r = TestRepo {
+            refs: vec!["refs/heads/master"],
+            config: vec![("init.defaultBranch", "stable")].into_iter().collect(),
+            ..Default::default()
+        };
+        assert_eq!(
+            default_branch(&r, None, CANDIDATES),
+            Ok(DefaultBranch::Candidate("master".into()))
+        );
+    }
+
+    #[test]
+    fn candidate_on_remote() {
+        let r = TestRepo {
+            remotes: vec!["origin"],
+            refs: vec!["refs/remotes/origin/trunk"],
+            ..Default::default()
+        };
+        assert_eq!(
+            default_branch(&r, None, CANDIDATES),
+            Ok(DefaultBranch::Candidate("trunk".into()))
+        );
+    }
+
+    #[test]
+    fn nothing_found() {
+        let r = TestRepo::default();
+        assert!(default_branch(&r, None, CANDIDATES).is_err());
+    }
+}
+
+fn upstream(repo: &dyn Repo, name: &str, head: git2::Oid, limit: Option<usize>) -> R<Upstream> {
+    // like for git, the branch tracks another one when merge is set, even if
+    // the remote does not exist anymore
+    if repo
+        .config_string(&format!("branch.{}.merge", name))
+        .is_err()
+    {
+        return Ok(Upstream::None);
+    }
+    let remote = match repo
+        .upstream_name(name)
+        .and_then(|u| repo.refname_to_id(&u).ok())
+    {
+        Some(remote) => remote,
+        None => return Ok(Upstream::Gone),
+    };
+    repo.ahead_behind(head, remote, limit)
+        .map(Upstream::from)
+        .map_err(|e| format!("{:?}", e))
+}
+
+/// The remote-tracking branch the branch is pushed to, like @{push} of git:
+/// on branch.<name>.pushRemote, remote.pushDefault or else the remote of the
+/// branch, under the name push.default chooses.
+pub fn push_target(repo: &dyn Repo, name: &str) -> Option<String> {
+    let config = |key: &str| repo.config_string(key).ok();
+    let remote = config(&format!("branch.{}.remote", name)).unwrap_or_else(|| "origin".into());
+    let push_remote = config(&format!("branch.{}.pushRemote", name))
+        .or_else(|| config("remote.pushDefault"))
+        .unwrap_or_else(|| remote.clone());
+    let triangular = push_remote != remote;
+    let current = format!("refs/remotes/{}/{}", push_remote, name);
+    match config("push.default").as_deref().unwrap_or("simple") {
+        "current" | "matching" => Some(current),
+        "upstream" | "tracking" if !triangular => repo.upstream_name(name),
+        "simple" if triangular => Some(current),
+        // the upstream, as long as it has the same name
+        "simple" => repo.upstream_name(name).filter(|u| *u == current),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod push_target {
+    use super::test_repo::TestRepo;
+    use super::*;
+
+    // feature is pulled from upstream/main
+    fn given(config: Vec<(&str, &str)>) -> Option<String> {
+        let mut config: HashMap<&str, &str> = config.into_iter().collect();
+        config.insert("branch.feature.remote", "upstream");
+        config.insert("branch.feature.merge", "refs/heads/main");
+        let r = TestRepo {
+            config,
+            ..Default::default()
+        };
+        push_target(&r, "feature")
+    }
+
+    #[test]
+    fn push_remote() {
+        let config = vec![
+            ("branch.feature.pushRemote", "fork"),
+            ("remote.pushDefault", "origin"),
+        ];
+        assert_eq!(given(config), Some("refs/remotes/fork/feature".into()));
+    }
+
+    #[test]
+    fn push_default() {
+        let config = vec![("remote.pushDefault", "origin")];
+        assert_eq!(given(config), Some("refs/remotes/origin/feature".into()));
+    }
+
+    #[test]
+    fn upstream_of_another_name() {
+        assert_eq!(given(vec![]), None);
+        let config = vec![("push.default", "upstream")];
+        assert_eq!(given(config), Some("refs/remotes/upstream/main".into()));
+        let config = vec![("push.default", "current")];
+        assert_eq!(given(config), Some("refs/remotes/upstream/feature".into()));
+    }
+
+    #[test]
+    fn nothing() {
+        let config = vec![
+            ("remote.pushDefault", "origin"),
+            ("push.default", "nothing"),
+        ];
+        assert_eq!(given(config), None);
+    }
+
+    #[test]
+    fn ahead_of_push_target() {
+        let r = TestRepo {
+            refs: vec![
+                "HEAD",
+                "refs/remotes/upstream/main",
+                "refs/remotes/origin/feature",
+            ],
+            config: vec![
+                ("branch.feature.remote", "upstream"),
+                ("branch.feature.merge", "refs/heads/main"),
+                ("remote.pushDefault", "origin"),
+            ]
+            .into_iter()
+            .collect(),
+            ahead_behind: Some((2, 5)),
+            ..Default::default()
+        };
+        let b = branch_status(&r, "feature", None, &[], None).unwrap();
+        assert_eq!(b.push_ahead, Some(2));
+        assert_eq!(
+            b.upstream,
+            Upstream::Diverged {
+                ahead: 2,
+                behind: 5
+            }
+        );
+    }
+
+    #[test]
+    fn push_target_is_upstream() {
+        let r = TestRepo {
+            refs: vec!["HEAD", "refs/remotes/origin/feature"],
+            config: vec![
+                ("branch.feature.remote", "origin"),
+                ("branch.feature.merge", "refs/heads/feature"),
+            ]
+            .into_iter()
+            .collect(),
+            ahead_behind: Some((2, 0)),
+            ..Default::default()
+        };
+        let b = branch_status(&r, "feature", None, &[], None).unwrap();
+        assert_eq!(b.push_ahead, None);
+    }
+}
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod upstream {
+    use super::test_repo::TestRepo;
+    use super::*;
+
+    // feature tracks origin/feature
+    fn given(refs: Vec<&str>, counts: Option<(usize, usize)>) -> R<Upstream> {
+        let r = TestRepo {
+            refs,
+            config: vec![
+                ("branch.feature.remote", "origin"),
+                ("branch.feature.merge", "refs/heads/feature"),
+            ]
+            .into_iter()
+            .collect(),
+            ahead_behind: counts,
+            ..Default::default()
+        };
+        branch_status(&r, "feature", None, &[], None).map(|b| b.upstream)
+    }
+
+    #[test]
+    fn no_upstream() {
+        let r = TestRepo {
+            refs: vec!["HEAD"],
+            ..Default::default()
+        };
+        let b = branch_status(&r, "feature", None, &[], None).unwrap();
+        assert_eq!(b.upstream, Upstream::None);
+    }
+
+    #[test]
+    fn gone() {
+        assert_eq!(given(vec!["HEAD"], Some((1, 0))), Ok(Upstream::Gone));
+    }
+
+    #[test]
+    fn in_sync() {
+        let refs = vec!["HEAD", "refs/remotes/origin/feature"];
+        assert_eq!(given(refs, Some((0, 0))), Ok(Upstream::InSync));
+    }
+
+    #[test]
+    fn diverged() {
+        let refs = vec!["HEAD", "refs/remotes/origin/feature"];
+        assert_eq!(
+            given(refs, Some((2, 1))),
+            Ok(Upstream::Diverged {
+                ahead: 2,
+                behind: 1
+            })
+        );
+    }
+
+    #[test]
+    fn failed_walk() {
+        let refs = vec!["HEAD", "refs/remotes/origin/feature"];
+        assert!(given(refs, None).is_err());
+    }
+}
+
+pub fn local_status(repo: &dyn Repo, untracked: Untracked) -> LocalStatus {
+    let mut status = repo
+        .statuses(Some(&mut status_options(untracked)))
+        .map(|statuses| count(statuses.iter().map(|e| e.status())))
+        .unwrap_or_default();
+    status.stashed = repo.stash_count().unwrap_or(0);
+    status
+}
+
+pub fn status_options(untracked: Untracked) -> git2::StatusOptions {
+    let mut options = git2::StatusOptions::new();
+    options
+        .include_ignored(false)
+        .recurse_ignored_dirs(false)
+        .include_untracked(untracked != Untracked::None)
+        .recurse_untracked_dirs(untracked == Untracked::All)
+        // a staged rename would be counted as a deleted and a new file
+        // otherwise, while like git status the files moved in the worktree
+        // are still deleted and untracked, which saves comparing them
+        .renames_head_to_index(true);
+    options
+}
+
+/// Count the statuses of the paths, without the stashes.
+pub fn count<I: Iterator<Item = git2::Status>>(statuses: I) -> LocalStatus {
     let is_staged = git2::Status::INDEX_NEW
         | git2::Status::INDEX_MODIFIED
         | git2::Status::INDEX_DELETED
@@ -202,27 +1485,77 @@ pub fn local_status(repo: &dyn Repo) -> LocalStatus {
         | git2::Status::WT_TYPECHANGE;
 
     let mut status = LocalStatus::new();
-    if let Ok(statuses) = repo.statuses(Some(
-        git2::StatusOptions::new()
-            .include_ignored(false)
-            .recurse_ignored_dirs(false)
-            .include_untracked(true)
-            .recurse_untracked_dirs(false),
-    )) {
-        for s in statuses.iter().map(|e| e.status()) {
-            if s.is_wt_new() {
-                status.untracked += 1;
-            }
-            if s.intersects(is_staged) {
-                status.staged += 1;
-            }
-            if s.intersects(is_modified) {
-                status.unstaged += 1;
+    for s in statuses {
+        if s.is_wt_new() {
+            status.untracked += 1;
+        }
+        if s.intersects(is_staged) {
+            status.staged += 1;
+        }
+        if s.intersects(is_modified) {
+            status.unstaged += 1;
+        }
+        let index = &mut status.index;
+        for (flag, n) in [
+            (git2::Status::INDEX_NEW, &mut index.new),
+            (git2::Status::INDEX_MODIFIED, &mut index.modified),
+            (git2::Status::INDEX_DELETED, &mut index.deleted),
+            (git2::Status::INDEX_RENAMED, &mut index.renamed),
+            (git2::Status::INDEX_TYPECHANGE, &mut index.typechange),
+        ] {
+            if s.contains(flag) {
+                *n += 1;
             }
-            if s.is_conflicted() {
-                status.unmerged += 1;
+        }
+        let worktree = &mut status.worktree;
+        for (flag, n) in [
+            (git2::Status::WT_MODIFIED, &mut worktree.modified),
+            (git2::Status::WT_DELETED, &mut worktree.deleted),
+            (git2::Status::WT_RENAMED, &mut worktree.renamed),
+            (git2::Status::WT_TYPECHANGE, &mut worktree.typechange),
+        ] {
+            if s.contains(flag) {
+                *n += 1;
             }
         }
+        if s.is_conflicted() {
+            status.unmerged += 1;
+        }
     }
     status
 }
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod local_status_tests {
+    use super::temp_repo;
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    const CONTENT: &str = "one\ntwo\nthree\nfour\nfive\n";
+
+    #[test]
+    fn renames() {
+        let repo = temp_repo::repository("renames");
+        let dir = repo.workdir().unwrap().to_owned();
+        let other = CONTENT.repeat(2);
+        temp_repo::commit(&repo, &[("staged", CONTENT), ("unstaged", &other)]);
+
+        fs::rename(dir.join("staged"), dir.join("moved")).unwrap();
+        let mut index = repo.index().unwrap();
+        index.remove_path(Path::new("staged")).unwrap();
+        index.add_path(Path::new("moved")).unwrap();
+        index.write().unwrap();
+        fs::rename(dir.join("unstaged"), dir.join("gone")).unwrap();
+
+        let status = local_status(&repo, Untracked::Normal);
+        assert_eq!(status.index.renamed, 1);
+        assert_eq!((status.worktree.renamed, status.worktree.deleted), (0, 1));
+        assert_eq!(
+            (status.staged, status.unstaged, status.untracked),
+            (1, 1, 1)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
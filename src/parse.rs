@@ -1,6 +1,43 @@
+use super::config;
 use super::model;
 use super::view;
 use ansi_term::Color;
+use std::iter::Peekable;
+use std::str::Chars;
+use std::time::Duration;
+
+pub struct Style<'a> {
+    pub colors: view::Colors,
+    pub branch_symbols: view::BranchSymbols<'a>,
+    pub status_symbols: view::StatusSymbols<'a>,
+    pub layout: view::Layout,
+}
+
+/// Parse and validate the style settings of the merged configuration.
+pub fn style(cfg: &config::Config) -> model::R<Style<'_>> {
+    Ok(Style {
+        colors: view::Colors {
+            shell: setting("shell", &cfg.shell, shell)?,
+            ..setting("colorscheme", &cfg.colorscheme, colors)?
+        },
+        branch_symbols: setting("branch-symbols", &cfg.branch_symbols, bs)?,
+        status_symbols: setting("status-symbols", &cfg.status_symbols, ss)?,
+        layout: setting("layout", &cfg.layout, layout)?,
+    })
+}
+
+/// Parse a setting of the merged configuration, naming it in the error.
+pub fn setting<'a, T>(
+    name: &str,
+    value: &'a Option<String>,
+    parse: fn(&'a str) -> model::R<T>,
+) -> model::R<T> {
+    value
+        .as_deref()
+        .ok_or_else(|| "missing value".to_owned())
+        .and_then(parse)
+        .map_err(|e| format!("invalid {}: {}", name, e))
+}
 
 pub fn colors(input: &str) -> model::R<view::Colors> {
     if input == "simple" {
@@ -9,6 +46,7 @@ pub fn colors(input: &str) -> model::R<view::Colors> {
             ok: Some(Color::Fixed(2)),
             high: Some(Color::Fixed(1)),
             normal: Some(Color::Fixed(3)),
+            ..view::NO_COLORS
         });
     }
 
@@ -22,6 +60,7 @@ pub fn colors(input: &str) -> model::R<view::Colors> {
             ok: Some(Color::Fixed(parts[0])),
             high: Some(Color::Fixed(parts[1])),
             normal: Some(Color::Fixed(parts[2])),
+            ..view::NO_COLORS
         }),
         l => Err(format!(
             "Unknown custom color input: {}. Expected 4 terms, but got {}.",
@@ -30,18 +69,98 @@ pub fn colors(input: &str) -> model::R<view::Colors> {
     }
 }
 
+pub fn shell(input: &str) -> model::R<view::Shell> {
+    match input {
+        "zsh" => Ok(view::Shell::Zsh),
+        "bash" => Ok(view::Shell::Bash),
+        "fish" => Ok(view::Shell::Fish),
+        "tmux" => Ok(view::Shell::Tmux),
+        "none" => Ok(view::Shell::None),
+        _ => Err(format!(
+            "Unknown shell: {}. Expected one of zsh, bash, fish, tmux, none.",
+            input
+        )),
+    }
+}
+
+/// The untracked mode of the merged configuration, where the untracked
+/// setting takes precedence over the older show-untracked one.
+pub fn untracked_mode(cfg: &config::Config) -> model::R<model::Untracked> {
+    match (&cfg.untracked, cfg.show_untracked) {
+        (Some(_), _) => setting("untracked", &cfg.untracked, untracked),
+        (None, Some(false)) => Ok(model::Untracked::None),
+        (None, _) => Ok(model::Untracked::Normal),
+    }
+}
+
+/// The values of --untracked-files of git status are accepted as well, as
+/// they come from status.showUntrackedFiles.
+pub fn untracked(input: &str) -> model::R<model::Untracked> {
+    match input {
+        "none" | "no" | "false" => Ok(model::Untracked::None),
+        "normal" | "true" => Ok(model::Untracked::Normal),
+        "all" => Ok(model::Untracked::All),
+        _ => Err(format!(
+            "Unknown untracked mode: {}. Expected one of none, normal, all.",
+            input
+        )),
+    }
+}
+
+/// The steps to describe a detached HEAD with, e.g. 'tag,nearest,remote', or
+/// 'none' for the abbreviated commit id.
+pub fn describe(input: &str) -> model::R<Vec<model::Describe>> {
+    input
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty() && *s != "none")
+        .map(|s| match s {
+            "tag" => Ok(model::Describe::Tag),
+            "nearest" => Ok(model::Describe::Nearest),
+            "remote" => Ok(model::Describe::Remote),
+            _ => Err(format!(
+                "Unknown describe step: {}. Expected tag, nearest, remote or none.",
+                s
+            )),
+        })
+        .collect()
+}
+
+/// The minutes after which the last fetch is flagged, where 0 never does.
+pub fn stale_fetch(cfg: &config::Config) -> Option<Duration> {
+    cfg.stale_fetch
+        .filter(|m| *m > 0)
+        .map(|m| Duration::from_secs(m * 60))
+}
+
+/// The minutes between the fetches in the background, where 0 never fetches.
+pub fn auto_fetch(cfg: &config::Config) -> Option<Duration> {
+    cfg.auto_fetch
+        .filter(|m| *m > 0)
+        .map(|m| Duration::from_secs(m * 60))
+}
+
 pub fn ss(input: &str) -> model::R<view::StatusSymbols> {
     let parts: Vec<&str> = input.split('|').collect();
     match parts.len() {
-        5 => Ok(view::StatusSymbols {
+        // the stash, unknown and the kinds of the changes were added later, so
+        // the ones left out are the defaults
+        5..=12 => Ok(view::StatusSymbols {
             nothing: parts[0],
             staged: parts[1],
             unmerged: parts[2],
             unstaged: parts[3],
             untracked: parts[4],
+            stashed: parts.get(5).unwrap_or(&"⚑"),
+            unknown: parts.get(6).unwrap_or(&"?"),
+            new: parts.get(7).unwrap_or(&"A"),
+            modified: parts.get(8).unwrap_or(&"M"),
+            deleted: parts.get(9).unwrap_or(&"D"),
+            renamed: parts.get(10).unwrap_or(&"R"),
+            typechange: parts.get(11).unwrap_or(&"T"),
         }),
         l => Err(format!(
-            "Unknown input format: {}. Expected 5 terms, but got {}.",
+            "Unknown input format: {}. Expected 5 to 12 terms, but got {}.",
             input, l
         )),
     }
@@ -50,13 +169,295 @@ pub fn ss(input: &str) -> model::R<view::StatusSymbols> {
 pub fn bs(input: &str) -> model::R<view::BranchSymbols> {
     let parts: Vec<&str> = input.split('|').collect();
     match parts.len() {
-        2 => Ok(view::BranchSymbols {
+        // the symbols for the default branch, the detached HEAD, the upstream,
+        // the push target and the last fetch were added later, so the ones left
+        // out are the defaults
+        2..=10 => Ok(view::BranchSymbols {
             ahead: parts[0],
             behind: parts[1],
+            default_ahead: parts.get(2).unwrap_or(&"⇡"),
+            default_behind: parts.get(3).unwrap_or(&"⇣"),
+            detached: parts.get(4).unwrap_or(&"➦"),
+            in_sync: parts.get(5).unwrap_or(&"="),
+            no_upstream: parts.get(6).unwrap_or(&""),
+            gone: parts.get(7).unwrap_or(&"⊘"),
+            push_ahead: parts.get(8).unwrap_or(&"⇈"),
+            stale: parts.get(9).unwrap_or(&"↻"),
         }),
         l => Err(format!(
-            "Unknown input format: {}. Expected 2 terms, but got {}.",
+            "Unknown input format: {}. Expected 2 to 10 terms, but got {}.",
             input, l
         )),
     }
 }
+
+/// Parse the prompt layout.  Fields are referenced as `{name}` and are
+/// rendered as is, `{?name:...}` renders the text in between only when the
+/// field is not empty.  Use `{{` and `}}` for literal braces.
+pub fn layout(input: &str) -> model::R<view::Layout> {
+    let mut chars = input.chars().peekable();
+    let segments = segments(&mut chars, false)?;
+    Ok(view::Layout(segments))
+}
+
+fn segments(chars: &mut Peekable<Chars>, nested: bool) -> model::R<Vec<view::Segment>> {
+    let mut result = Vec::new();
+    let mut text = String::new();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                text.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                text.push('}');
+            }
+            '{' => {
+                if !text.is_empty() {
+                    result.push(view::Segment::Text(text.split_off(0)));
+                }
+                result.push(placeholder(chars)?);
+            }
+            '}' if nested => {
+                if !text.is_empty() {
+                    result.push(view::Segment::Text(text));
+                }
+                return Ok(result);
+            }
+            '}' => return Err("Unexpected '}' in the layout, use '}}' for a literal one.".into()),
+            c => text.push(c),
+        }
+    }
+    if nested {
+        return Err("Unclosed '{' in the layout.".into());
+    }
+    if !text.is_empty() {
+        result.push(view::Segment::Text(text));
+    }
+    Ok(result)
+}
+
+fn placeholder(chars: &mut Peekable<Chars>) -> model::R<view::Segment> {
+    let conditional = chars.peek() == Some(&'?');
+    if conditional {
+        chars.next();
+    }
+
+    let mut name = String::new();
+    loop {
+        match chars.next() {
+            Some('}') if !conditional => return Ok(view::Segment::Field(field(&name)?)),
+            Some(':') if conditional => {
+                let f = field(&name)?;
+                return Ok(view::Segment::Cond(f, segments(chars, true)?));
+            }
+            Some(c) if c == '_' || c.is_ascii_alphanumeric() => name.push(c),
+            Some(c) => {
+                return Err(format!(
+                    "Unexpected '{}' after '{}' in the layout placeholder.",
+                    c, name
+                ))
+            }
+            None => return Err("Unclosed '{' in the layout.".into()),
+        }
+    }
+}
+
+fn field(name: &str) -> model::R<view::Field> {
+    match name {
+        "state" => Ok(view::Field::State),
+        "branch" => Ok(view::Field::Branch),
+        "upstream" => Ok(view::Field::Upstream),
+        "ahead_behind" => Ok(view::Field::AheadBehind),
+        "default_ahead_behind" => Ok(view::Field::DefaultAheadBehind),
+        "default_branch" => Ok(view::Field::DefaultBranch),
+        "fetch" => Ok(view::Field::Fetch),
+        "local" => Ok(view::Field::Local),
+        "local_detailed" => Ok(view::Field::LocalDetailed),
+        "onto" => Ok(view::Field::Onto),
+        _ => Err(format!(
+            "Unknown layout field: '{}'. Expected one of state, branch, upstream, ahead_behind, default_ahead_behind, default_branch, fetch, local, local_detailed, onto.",
+            name
+        )),
+    }
+}
+
+#[cfg(test)]
+mod setting_tests {
+    use super::*;
+
+    #[test]
+    fn error_names_the_setting() {
+        assert_eq!(
+            setting("shell", &Some("csh".to_owned()), shell),
+            Err(
+                "invalid shell: Unknown shell: csh. Expected one of zsh, bash, fish, tmux, none."
+                    .into()
+            )
+        );
+        assert_eq!(
+            setting("shell", &None, shell),
+            Err("invalid shell: missing value".into())
+        );
+    }
+}
+
+#[cfg(test)]
+mod symbols_tests {
+    use super::*;
+
+    #[test]
+    fn accepted_counts() {
+        assert!(ss("✔|●|✖|✚|…").is_ok());
+        let ss8 = ss("✔|●|✖|✚|…|⚑|?|N").unwrap();
+        assert_eq!((ss8.new, ss8.modified, ss8.typechange), ("N", "M", "T"));
+        assert_eq!(
+            ss("✔|●|✖|✚").err(),
+            Some("Unknown input format: ✔|●|✖|✚. Expected 5 to 12 terms, but got 4.".into())
+        );
+        assert!(ss("✔|●|✖|✚|…|⚑|?|A|M|D|R|T|X").is_err());
+        let bs3 = bs("↑|↓|⇡").unwrap();
+        assert_eq!((bs3.default_ahead, bs3.default_behind), ("⇡", "⇣"));
+        assert_eq!(
+            bs("↑").err(),
+            Some("Unknown input format: ↑. Expected 2 to 10 terms, but got 1.".into())
+        );
+        assert!(bs("↑|↓|⇡|⇣|➦|=||⊘|⇈|↻|x").is_err());
+    }
+}
+
+#[cfg(test)]
+mod shell_tests {
+    use super::*;
+
+    #[test]
+    fn known() {
+        assert_eq!(shell("zsh"), Ok(view::Shell::Zsh));
+        assert_eq!(shell("bash"), Ok(view::Shell::Bash));
+        assert_eq!(shell("fish"), Ok(view::Shell::Fish));
+        assert_eq!(shell("tmux"), Ok(view::Shell::Tmux));
+        assert_eq!(shell("none"), Ok(view::Shell::None));
+    }
+
+    #[test]
+    fn unknown() {
+        assert!(shell("csh").is_err());
+    }
+}
+
+#[cfg(test)]
+mod untracked_tests {
+    use super::*;
+    use model::Untracked;
+
+    #[test]
+    fn modes() {
+        assert_eq!(untracked("none"), Ok(Untracked::None));
+        assert_eq!(untracked("no"), Ok(Untracked::None));
+        assert_eq!(untracked("normal"), Ok(Untracked::Normal));
+        assert_eq!(untracked("all"), Ok(Untracked::All));
+        assert!(untracked("some").is_err());
+    }
+
+    #[test]
+    fn older_setting() {
+        let cfg = |untracked: Option<&str>, show_untracked| config::Config {
+            untracked: untracked.map(str::to_owned),
+            show_untracked,
+            ..Default::default()
+        };
+        assert_eq!(untracked_mode(&cfg(None, None)), Ok(Untracked::Normal));
+        assert_eq!(untracked_mode(&cfg(None, Some(false))), Ok(Untracked::None));
+        assert_eq!(
+            untracked_mode(&cfg(Some("all"), Some(false))),
+            Ok(Untracked::All)
+        );
+    }
+}
+
+#[cfg(test)]
+mod describe_tests {
+    use super::*;
+    use model::Describe;
+
+    #[test]
+    fn steps() {
+        assert_eq!(
+            describe("tag,nearest,remote"),
+            Ok(vec![Describe::Tag, Describe::Nearest, Describe::Remote])
+        );
+        assert_eq!(
+            describe("remote, tag"),
+            Ok(vec![Describe::Remote, Describe::Tag])
+        );
+        assert_eq!(describe("none"), Ok(vec![]));
+        assert!(describe("branch").is_err());
+    }
+}
+
+#[cfg(test)]
+mod layout_tests {
+    use super::*;
+    use view::{Field, Segment};
+
+    fn text(t: &str) -> Segment {
+        Segment::Text(t.to_owned())
+    }
+
+    #[test]
+    fn default_matches_the_view() {
+        let input = concat!(
+            "{?state:{state} }{?branch:{branch} }{?upstream:{upstream} }",
+            "{?ahead_behind:{ahead_behind} }{?default_ahead_behind:{default_ahead_behind} }",
+            "{?fetch:{fetch} }{?local:{local} }"
+        );
+        assert_eq!(layout(input), Ok(view::Layout::default()));
+        assert_eq!(
+            layout(&config::Config::defaults().layout.unwrap()),
+            Ok(view::Layout::default())
+        );
+    }
+
+    #[test]
+    fn fields_and_text() {
+        assert_eq!(
+            layout("on {branch} [{local}]").unwrap().0,
+            vec![
+                text("on "),
+                Segment::Field(Field::Branch),
+                text(" ["),
+                Segment::Field(Field::Local),
+                text("]"),
+            ]
+        );
+    }
+
+    #[test]
+    fn conditional() {
+        assert_eq!(
+            layout("{branch}{?state:|{state}}").unwrap().0,
+            vec![
+                Segment::Field(Field::Branch),
+                Segment::Cond(Field::State, vec![text("|"), Segment::Field(Field::State)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn escaped_braces() {
+        assert_eq!(
+            layout("{{{branch}}}").unwrap().0,
+            vec![text("{"), Segment::Field(Field::Branch), text("}"),]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert!(layout("{branch").is_err());
+        assert!(layout("{?state:{state}").is_err());
+        assert!(layout("branch}").is_err());
+        assert!(layout("{foo}").is_err());
+        assert!(layout("{state:x}").is_err());
+    }
+}
@@ -0,0 +1,205 @@
+//! The untracked cache is an extension of the index, which remembers the
+//! untracked files of the directories which did not change since.  libgit2
+//! neither reads nor writes it, so git itself is asked for the status when it
+//! is turned on with core.untrackedCache.
+//!
+//! git status writes the cache back to the index, so that the next prompt only
+//! scans the directories which changed since.  It takes the lock of the index
+//! for that, so it is told not to when another git command holds the lock
+//! already, which would fail otherwise.
+use super::model::{LocalStatus, Repo, Untracked, R};
+use std::process::{Command, Stdio};
+
+/// Whether core.untrackedCache is true, or keep, which uses the cache when it
+/// is in the index already.  The latter is also the default of git, but the
+/// cache is rarely there then, so it is only used when set explicitly.
+pub fn enabled(repo: &git2::Repository) -> bool {
+    let cfg = match repo.config() {
+        Ok(c) => c,
+        Err(_) => return false,
+    };
+    match cfg.get_bool("core.untrackedCache") {
+        Ok(b) => b,
+        Err(_) => cfg
+            .get_string("core.untrackedCache")
+            .map(|v| v.eq_ignore_ascii_case("keep"))
+            .unwrap_or(false),
+    }
+}
+
+pub fn local_status(repo: &git2::Repository, untracked: Untracked) -> R<LocalStatus> {
+    let workdir = repo.workdir().ok_or("the repository is bare")?;
+    let mut git = Command::new("git");
+    if repo.path().join("index.lock").exists() {
+        git.arg("--no-optional-locks");
+    }
+    let output = git
+        .args(["status", "--porcelain", "-z"])
+        .arg(match untracked {
+            Untracked::None => "--untracked-files=no",
+            Untracked::Normal => "--untracked-files=normal",
+            Untracked::All => "--untracked-files=all",
+        })
+        .current_dir(workdir)
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .map_err(|e| format!("git status: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("git status: {}", output.status));
+    }
+    let mut status = parse(&String::from_utf8_lossy(&output.stdout));
+    status.stashed = repo.stash_count().unwrap_or(0);
+    Ok(status)
+}
+
+// the porcelain format of git status, with NUL terminated entries
+fn parse(out: &str) -> LocalStatus {
+    let mut status = LocalStatus::new();
+    let mut entries = out.split('\0').filter(|e| !e.is_empty());
+    while let Some(e) = entries.next() {
+        let (x, y) = match e.as_bytes() {
+            [x, y, b' ', ..] => (*x, *y),
+            _ => continue,
+        };
+        match (x, y) {
+            (b'?', b'?') => status.untracked += 1,
+            (b'!', b'!') => {}
+            (b'D', b'D') | (b'A', b'A') | (b'U', _) | (_, b'U') => status.unmerged += 1,
+            _ => {
+                if b"MADRCT".contains(&x) {
+                    status.staged += 1;
+                }
+                if b"MDRT".contains(&y) {
+                    status.unstaged += 1;
+                }
+                // a copy is a new file to libgit2
+                match x {
+                    b'A' | b'C' => status.index.new += 1,
+                    b'M' => status.index.modified += 1,
+                    b'D' => status.index.deleted += 1,
+                    b'R' => status.index.renamed += 1,
+                    b'T' => status.index.typechange += 1,
+                    _ => {}
+                }
+                // only a file added with git add -N is renamed in the worktree
+                match y {
+                    b'M' => status.worktree.modified += 1,
+                    b'D' => status.worktree.deleted += 1,
+                    b'R' => status.worktree.renamed += 1,
+                    b'T' => status.worktree.typechange += 1,
+                    _ => {}
+                }
+            }
+        }
+        // followed by the original path
+        if x == b'R' || x == b'C' || y == b'R' {
+            entries.next();
+        }
+    }
+    status
+}
+
+#[cfg(test)]
+mod untracked_cache_tests {
+    use super::super::model::{temp_repo, Changes};
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn porcelain() {
+        let out = "M  staged\0 M unstaged\0MM both\0UU conflict\0AA added\0?? new/\0\
+                   R  to\0from\0 R moved\0added\0!! ignored\0";
+        assert_eq!(
+            parse(out),
+            LocalStatus {
+                staged: 3,
+                unmerged: 2,
+                unstaged: 3,
+                untracked: 1,
+                stashed: 0,
+                index: Changes {
+                    modified: 2,
+                    renamed: 1,
+                    ..Default::default()
+                },
+                worktree: Changes {
+                    modified: 2,
+                    renamed: 1,
+                    ..Default::default()
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn setting() {
+        let repo = temp_repo::repository("untracked-setting");
+        assert!(!enabled(&repo));
+        let mut cfg = repo.config().unwrap();
+        cfg.set_str("core.untrackedCache", "keep").unwrap();
+        assert!(enabled(&repo));
+        cfg.set_bool("core.untrackedCache", false).unwrap();
+        assert!(!enabled(&repo));
+        fs::remove_dir_all(repo.workdir().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn index_is_written() {
+        let repo = temp_repo::repository("untracked-written");
+        let dir = repo.workdir().unwrap().to_owned();
+        repo.config()
+            .unwrap()
+            .set_bool("core.untrackedCache", true)
+            .unwrap();
+        fs::write(dir.join("a"), "").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("a")).unwrap();
+        index.write().unwrap();
+        fs::create_dir(dir.join("new")).unwrap();
+        fs::write(dir.join("new").join("b"), "").unwrap();
+
+        fs::write(dir.join("new").join("c"), "").unwrap();
+
+        let counts = || {
+            let status = local_status(&repo, Untracked::Normal).unwrap();
+            assert_eq!((status.staged, status.untracked), (1, 1));
+            assert_eq!(local_status(&repo, Untracked::All).unwrap().untracked, 2);
+            assert_eq!(local_status(&repo, Untracked::None).unwrap().untracked, 0);
+        };
+        let cached = || {
+            let index = fs::read(dir.join(".git").join("index")).unwrap();
+            index.windows(4).any(|w| w == b"UNTR")
+        };
+        // not while another git command is about to write the index
+        let lock = dir.join(".git").join("index.lock");
+        fs::write(&lock, "").unwrap();
+        counts();
+        assert!(!cached());
+
+        fs::remove_file(&lock).unwrap();
+        counts();
+        assert!(cached());
+        counts();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn renames() {
+        let repo = temp_repo::repository("untracked-renames");
+        let dir = repo.workdir().unwrap().to_owned();
+        temp_repo::commit(&repo, &[("a", "one\ntwo\nthree\nfour\nfive\n")]);
+        let moved = Command::new("git")
+            .args(["mv", "a", "b"])
+            .current_dir(&dir)
+            .status()
+            .unwrap();
+        assert!(moved.success());
+
+        let status = local_status(&repo, Untracked::Normal).unwrap();
+        assert_eq!(status.index.renamed, 1);
+        assert_eq!((status.staged, status.untracked), (1, 0));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,6 +1,8 @@
 use super::model::*;
 use ansi_term::Color;
+use std::borrow::Cow;
 use std::fmt::{self, Display, Formatter};
+use std::time::Duration;
 
 #[derive(Clone, Debug)]
 pub struct Prompt<'a> {
@@ -8,10 +10,20 @@ pub struct Prompt<'a> {
     // it only makes sense to have the branch status when the repo is OK
     pub branch: Option<BranchStatus>,
     pub local: Option<LocalStatus>,
+    // the segments which did not finish before the timeout
+    pub branch_timed_out: bool,
+    pub local_timed_out: bool,
+    // the untracked files are not counted when they are not looked for
+    pub untracked: Untracked,
+    // the upstream segment is only shown when turned on
+    pub show_upstream: bool,
+    // the last fetch is flagged when it is older than this
+    pub stale_fetch: Option<Duration>,
 
     pub colors: Colors,
     pub branch_symbols: BranchSymbols<'a>,
     pub status_symbols: StatusSymbols<'a>,
+    pub layout: Layout,
 }
 
 impl<'a> Prompt<'a> {
@@ -20,10 +32,23 @@ impl<'a> Prompt<'a> {
             repo: repo.clone(),
             branch: None,
             local: None,
+            branch_timed_out: false,
+            local_timed_out: false,
+            untracked: Untracked::Normal,
+            show_upstream: false,
+            stale_fetch: None,
             colors: NO_COLORS,
             branch_symbols: BranchSymbols {
                 ahead: "↑",
                 behind: "↓",
+                default_ahead: "⇡",
+                default_behind: "⇣",
+                detached: "➦",
+                in_sync: "=",
+                no_upstream: "",
+                gone: "⊘",
+                push_ahead: "⇈",
+                stale: "↻",
             },
             status_symbols: StatusSymbols {
                 nothing: "✔",
@@ -31,7 +56,15 @@ impl<'a> Prompt<'a> {
                 unmerged: "✖",
                 unstaged: "✚",
                 untracked: "…",
+                stashed: "⚑",
+                unknown: "?",
+                new: "A",
+                modified: "M",
+                deleted: "D",
+                renamed: "R",
+                typechange: "T",
             },
+            layout: Layout::default(),
         }
     }
 
@@ -47,6 +80,36 @@ impl<'a> Prompt<'a> {
         p
     }
 
+    pub fn with_branch_timed_out(&self) -> Prompt<'a> {
+        let mut p = self.clone();
+        p.branch_timed_out = true;
+        p
+    }
+
+    pub fn with_local_timed_out(&self) -> Prompt<'a> {
+        let mut p = self.clone();
+        p.local_timed_out = true;
+        p
+    }
+
+    pub fn with_untracked(&self, untracked: Untracked) -> Prompt<'a> {
+        let mut p = self.clone();
+        p.untracked = untracked;
+        p
+    }
+
+    pub fn with_upstream_shown(&self, show: bool) -> Prompt<'a> {
+        let mut p = self.clone();
+        p.show_upstream = show;
+        p
+    }
+
+    pub fn with_stale_fetch(&self, after: Option<Duration>) -> Prompt<'a> {
+        let mut p = self.clone();
+        p.stale_fetch = after;
+        p
+    }
+
     pub fn with_style(
         &self,
         c: &Colors,
@@ -59,50 +122,144 @@ impl<'a> Prompt<'a> {
         p.status_symbols = ss.clone();
         p
     }
-}
 
-impl<'a> Display for Prompt<'a> {
-    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        let state = format!(
-            "{}",
-            RepoStateView {
+    pub fn with_layout(&self, layout: &Layout) -> Prompt<'a> {
+        let mut p = self.clone();
+        p.layout = layout.clone();
+        p
+    }
+
+    fn field(&self, field: &Field) -> String {
+        let (timed_out, segment): (bool, &[Field]) = match field {
+            Field::AheadBehind | Field::DefaultAheadBehind | Field::DefaultBranch => (
+                self.branch_timed_out,
+                &[
+                    Field::AheadBehind,
+                    Field::DefaultAheadBehind,
+                    Field::DefaultBranch,
+                ],
+            ),
+            Field::Local | Field::LocalDetailed => {
+                (self.local_timed_out, &[Field::Local, Field::LocalDetailed])
+            }
+            Field::State | Field::Branch | Field::Upstream | Field::Fetch | Field::Onto => {
+                (false, &[])
+            }
+        };
+        if timed_out {
+            // one marker for the segment rather than one for each of its
+            // fields, so it goes to the first of them in the layout
+            if self.layout.first_of(segment) != Some(*field) {
+                return String::new();
+            }
+            return View {
+                text: self.status_symbols.unknown,
+                color: self.colors.normal,
+                shell: self.colors.shell,
+            }
+            .to_string();
+        }
+
+        match field {
+            Field::State => RepoStateView {
                 model: self.repo.state,
+                progress: self.repo.progress.clone(),
                 colors: &self.colors,
             }
-        );
-        let repo = format!(
-            "{}",
-            RepoStatusView {
+            .to_string(),
+            Field::Branch => RepoStatusView {
                 model: self.repo.clone(),
+                symbols: &self.branch_symbols,
                 colors: &self.colors,
             }
-        );
-        let branch = format!(
-            "{}",
-            BranchStatusView {
+            .to_string(),
+            Field::Upstream => UpstreamView {
+                branch: self.repo.branch.as_deref(),
+                upstream: self.repo.upstream.as_deref().filter(|_| self.show_upstream),
+                remote: self.repo.remote.as_deref(),
+                colors: &self.colors,
+            }
+            .to_string(),
+            Field::Fetch => FetchView {
+                model: self.repo.last_fetch,
+                after: self.stale_fetch,
+                symbols: &self.branch_symbols,
+                colors: &self.colors,
+            }
+            .to_string(),
+            Field::AheadBehind => BranchStatusView {
                 model: self.branch.clone(),
                 symbols: &self.branch_symbols,
                 colors: &self.colors,
             }
-        );
-        let local = self
-            .local
-            .clone()
-            .map(|status| LocalStatusView {
-                model: status,
-                symbols: &self.status_symbols,
+            .to_string(),
+            Field::DefaultAheadBehind => DefaultBranchStatusView {
+                model: self.branch.clone(),
+                symbols: &self.branch_symbols,
                 colors: &self.colors,
-            })
-            .map(|v| format!("{}", v))
-            .unwrap_or_default();
+            }
+            .to_string(),
+            Field::DefaultBranch => self
+                .branch
+                .as_ref()
+                .and_then(|b| b.default_branch.as_ref())
+                .map(|d| {
+                    View {
+                        text: d.name(),
+                        color: self.colors.normal,
+                        shell: self.colors.shell,
+                    }
+                    .to_string()
+                })
+                .unwrap_or_default(),
+            Field::Onto => self
+                .repo
+                .rebase
+                .as_ref()
+                .and_then(|r| r.onto.as_deref())
+                .map(|onto| {
+                    View {
+                        text: onto,
+                        color: self.colors.normal,
+                        shell: self.colors.shell,
+                    }
+                    .to_string()
+                })
+                .unwrap_or_default(),
+            Field::Local | Field::LocalDetailed => self
+                .local
+                .clone()
+                .map(|status| LocalStatusView {
+                    model: status,
+                    detailed: *field == Field::LocalDetailed,
+                    untracked: self.untracked,
+                    symbols: &self.status_symbols,
+                    colors: &self.colors,
+                })
+                .map(|v| v.to_string())
+                .unwrap_or_default(),
+        }
+    }
 
-        let mut r = String::new();
-        for i in vec![state, repo, branch, local].iter() {
-            if i != "" {
-                r.push_str(i);
-                r.push(' ');
+    fn render(&self, segments: &[Segment], out: &mut String) {
+        for s in segments {
+            match s {
+                Segment::Text(t) => out.push_str(t),
+                Segment::Field(f) => out.push_str(&self.field(f)),
+                Segment::Cond(f, inner) => {
+                    if !self.field(f).is_empty() {
+                        self.render(inner, out);
+                    }
+                }
             }
         }
+    }
+}
+
+impl<'a> Display for Prompt<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let mut r = String::new();
+        self.render(&self.layout.0, &mut r);
         write!(f, "{}", r)
     }
 }
@@ -116,11 +273,20 @@ mod print_tests {
     fn prompt_is_respaced() {
         let p = Prompt::new(&RepoStatus {
             branch: Some("master".into()),
+            detached: false,
+            upstream: None,
+            remote: None,
+            last_fetch: None,
             state: git2::RepositoryState::Clean,
+            progress: None,
+            rebase: None,
         })
         .with_branch(Some(BranchStatus {
-            ahead: 1,
-            behind: 4,
+            upstream: Upstream::Diverged {
+                ahead: 1,
+                behind: 4,
+            },
+            ..Default::default()
         }))
         .with_local(Some(LOCAL_CLEAN))
         .with_style(
@@ -128,6 +294,14 @@ mod print_tests {
             &BranchSymbols {
                 ahead: "↑",
                 behind: "↓",
+                default_ahead: "⇡",
+                default_behind: "⇣",
+                detached: "➦",
+                in_sync: "=",
+                no_upstream: "",
+                gone: "⊘",
+                push_ahead: "⇈",
+                stale: "↻",
             },
             &StatusSymbols {
                 nothing: "✓",
@@ -135,6 +309,13 @@ mod print_tests {
                 unmerged: "m",
                 unstaged: "u",
                 untracked: ".",
+                stashed: "$",
+                unknown: "?",
+                new: "A",
+                modified: "M",
+                deleted: "D",
+                renamed: "R",
+                typechange: "T",
             },
         );
         assert_eq!(p.to_string(), "master ↑1↓4 ✓ ");
@@ -144,18 +325,34 @@ mod print_tests {
     fn prompt_is_trimmed() {
         let p = Prompt::new(&RepoStatus {
             branch: None,
+            detached: false,
+            upstream: None,
+            remote: None,
+            last_fetch: None,
             state: git2::RepositoryState::Clean,
+            progress: None,
+            rebase: None,
         })
         .with_local(Some(LocalStatus {
             staged: 1,
             unmerged: 0,
             unstaged: 0,
             untracked: 3,
+            stashed: 0,
+            ..Default::default()
         }));
         let c = &NO_COLORS;
         let bs = BranchSymbols {
             ahead: "↑",
             behind: "↓",
+            default_ahead: "⇡",
+            default_behind: "⇣",
+            detached: "➦",
+            in_sync: "=",
+            no_upstream: "",
+            gone: "⊘",
+            push_ahead: "⇈",
+            stale: "↻",
         };
         let ss = StatusSymbols {
             nothing: "✓",
@@ -163,9 +360,207 @@ mod print_tests {
             unmerged: "m",
             unstaged: "u",
             untracked: ".",
+            stashed: "$",
+            unknown: "?",
+            new: "A",
+            modified: "M",
+            deleted: "D",
+            renamed: "R",
+            typechange: "T",
         };
         assert_eq!(p.with_style(&c, &bs, &ss).to_string(), "s1. ");
     }
+
+    #[test]
+    fn custom_layout() {
+        let p = Prompt::new(&RepoStatus {
+            branch: Some("master".into()),
+            detached: false,
+            upstream: None,
+            remote: None,
+            last_fetch: None,
+            state: git2::RepositoryState::Rebase,
+            progress: None,
+            rebase: None,
+        })
+        .with_branch(Some(BranchStatus::default()))
+        .with_local(Some(LOCAL_CLEAN))
+        .with_layout(&Layout(vec![
+            Segment::Text("(".to_owned()),
+            Segment::Field(Field::Branch),
+            Segment::Cond(
+                Field::State,
+                vec![Segment::Text("|".to_owned()), Segment::Field(Field::State)],
+            ),
+            Segment::Cond(
+                Field::AheadBehind,
+                vec![
+                    Segment::Text(" ".to_owned()),
+                    Segment::Field(Field::AheadBehind),
+                ],
+            ),
+            Segment::Text(") ".to_owned()),
+            Segment::Field(Field::Local),
+        ]));
+        assert_eq!(p.to_string(), "(master|rebase) ✔");
+    }
+
+    #[test]
+    fn rebase_onto() {
+        let layout = Layout(vec![
+            Segment::Field(Field::Branch),
+            Segment::Cond(
+                Field::Onto,
+                vec![
+                    Segment::Text(" onto ".to_owned()),
+                    Segment::Field(Field::Onto),
+                ],
+            ),
+        ]);
+        let p = Prompt::new(&RepoStatus {
+            branch: Some("16fa43d".into()),
+            detached: false,
+            upstream: None,
+            remote: None,
+            last_fetch: None,
+            state: git2::RepositoryState::RebaseMerge,
+            progress: None,
+            rebase: Some(Rebase {
+                head_name: Some("feature-x".into()),
+                onto: Some("abc1234".into()),
+            }),
+        })
+        .with_layout(&layout);
+        assert_eq!(p.to_string(), "feature-x onto abc1234");
+    }
+
+    #[test]
+    fn upstream_is_toggled() {
+        let p = Prompt::new(&RepoStatus {
+            branch: Some("fix".into()),
+            detached: false,
+            upstream: Some("origin/bugfix-1234".into()),
+            remote: Some("origin".into()),
+            last_fetch: None,
+            state: git2::RepositoryState::Clean,
+            progress: None,
+            rebase: None,
+        });
+        assert_eq!(p.to_string(), "fix ");
+        assert_eq!(
+            p.with_upstream_shown(true).to_string(),
+            "fix origin/bugfix-1234 "
+        );
+    }
+
+    #[test]
+    fn local_detailed() {
+        let p = Prompt::new(&RepoStatus {
+            branch: Some("master".into()),
+            detached: false,
+            upstream: None,
+            remote: None,
+            last_fetch: None,
+            state: git2::RepositoryState::Clean,
+            progress: None,
+            rebase: None,
+        })
+        .with_local(Some(LocalStatus {
+            staged: 2,
+            index: Changes {
+                new: 1,
+                renamed: 1,
+                ..Changes::NONE
+            },
+            ..LOCAL_CLEAN
+        }));
+        let layout = |field| Layout(vec![Segment::Field(field)]);
+        assert_eq!(p.with_layout(&layout(Field::Local)).to_string(), "●2");
+        assert_eq!(
+            p.with_layout(&layout(Field::LocalDetailed)).to_string(),
+            "●A1R1"
+        );
+    }
+
+    #[test]
+    fn default_branch() {
+        let layout = Layout(vec![
+            Segment::Field(Field::AheadBehind),
+            Segment::Cond(
+                Field::DefaultBranch,
+                vec![
+                    Segment::Text(" ".to_owned()),
+                    Segment::Field(Field::DefaultBranch),
+                    Segment::Field(Field::DefaultAheadBehind),
+                ],
+            ),
+        ]);
+        let p = Prompt::new(&RepoStatus {
+            branch: Some("feature".into()),
+            detached: false,
+            upstream: None,
+            remote: None,
+            last_fetch: None,
+            state: git2::RepositoryState::Clean,
+            progress: None,
+            rebase: None,
+        })
+        .with_layout(&layout);
+
+        let with_upstream = p.with_branch(Some(BranchStatus {
+            upstream: Upstream::Diverged {
+                ahead: 1,
+                behind: 0,
+            },
+            ..Default::default()
+        }));
+        assert_eq!(with_upstream.to_string(), "↑1");
+
+        let with_default = p.with_branch(Some(BranchStatus {
+            upstream: Upstream::Diverged {
+                ahead: 1,
+                behind: 0,
+            },
+            default_behind: 3,
+            default_branch: Some(DefaultBranch::RemoteHead("main".into())),
+            ..Default::default()
+        }));
+        assert_eq!(with_default.to_string(), "↑1 main⇣3");
+    }
+
+    #[test]
+    fn timed_out() {
+        let p = Prompt::new(&RepoStatus {
+            branch: Some("master".into()),
+            detached: false,
+            upstream: None,
+            remote: None,
+            last_fetch: None,
+            state: git2::RepositoryState::Clean,
+            progress: None,
+            rebase: None,
+        });
+        assert_eq!(p.to_string(), "master ");
+        assert_eq!(p.with_branch_timed_out().to_string(), "master ? ");
+        assert_eq!(
+            p.with_branch(Some(BranchStatus::default()))
+                .with_local_timed_out()
+                .to_string(),
+            "master ? "
+        );
+
+        let layout = Layout(vec![
+            Segment::Field(Field::DefaultBranch),
+            Segment::Field(Field::Local),
+            Segment::Field(Field::AheadBehind),
+            Segment::Field(Field::LocalDetailed),
+        ]);
+        let p = p
+            .with_layout(&layout)
+            .with_branch_timed_out()
+            .with_local_timed_out();
+        assert_eq!(p.to_string(), "??");
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -173,14 +568,209 @@ pub struct Colors {
     pub ok: Option<Color>,
     pub high: Option<Color>,
     pub normal: Option<Color>,
+    pub shell: Shell,
 }
 
 pub const NO_COLORS: Colors = Colors {
     ok: None,
     high: None,
     normal: None,
+    shell: Shell::None,
 };
 
+/// The shell the prompt is printed for, which decides how the zero-width color
+/// sequences are escaped so that the shell can calculate the prompt width.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shell {
+    // the prompt is expanded, so '%' in e.g. a branch name has to be escaped
+    Zsh,
+    // '\[' and '\]' are only understood if they are written into PS1, but
+    // readline itself understands the bytes they stand for, which also works
+    // when the output of a command substitution is part of PS1.
+    Bash,
+    // fish calculates the width of the prompt by itself
+    Fish,
+    Tmux,
+    None,
+}
+
+impl Shell {
+    fn paint(self, color: Color, text: &str) -> String {
+        match self {
+            Shell::Zsh => format!(
+                "%{{{}%}}{}%{{{}%}}",
+                color.prefix(),
+                self.escape(text),
+                color.suffix()
+            ),
+            Shell::Bash => format!(
+                "\x01{}\x02{}\x01{}\x02",
+                color.prefix(),
+                text,
+                color.suffix()
+            ),
+            Shell::Tmux => format!(
+                "#[fg={}]{}#[fg=default]",
+                tmux_color(color),
+                self.escape(text)
+            ),
+            Shell::Fish | Shell::None => color.paint(text).to_string(),
+        }
+    }
+
+    /// The text as it is printed by the shell, which only matters for the
+    /// text which does not come from the layout.
+    fn escape(self, text: &str) -> Cow<'_, str> {
+        match self {
+            Shell::Zsh if text.contains('%') => Cow::Owned(text.replace('%', "%%")),
+            Shell::Tmux if text.contains('#') => Cow::Owned(text.replace('#', "##")),
+            _ => Cow::Borrowed(text),
+        }
+    }
+}
+
+fn tmux_color(color: Color) -> String {
+    match color {
+        Color::Black => "black".to_owned(),
+        Color::Red => "red".to_owned(),
+        Color::Green => "green".to_owned(),
+        Color::Yellow => "yellow".to_owned(),
+        Color::Blue => "blue".to_owned(),
+        Color::Purple => "magenta".to_owned(),
+        Color::Cyan => "cyan".to_owned(),
+        Color::White => "white".to_owned(),
+        Color::Fixed(n) => format!("colour{}", n),
+        Color::RGB(r, g, b) => format!("#{:02x}{:02x}{:02x}", r, g, b),
+    }
+}
+
+#[cfg(test)]
+mod shell_tests {
+    use super::*;
+
+    #[test]
+    fn zsh() {
+        assert_eq!(
+            Shell::Zsh.paint(Color::Fixed(1), "foo"),
+            "%{\u{1b}[38;5;1m%}foo%{\u{1b}[0m%}"
+        );
+    }
+
+    #[test]
+    fn bash() {
+        assert_eq!(
+            Shell::Bash.paint(Color::Fixed(1), "foo"),
+            "\u{1}\u{1b}[38;5;1m\u{2}foo\u{1}\u{1b}[0m\u{2}"
+        );
+    }
+
+    #[test]
+    fn zsh_percent() {
+        assert_eq!(
+            Shell::Zsh.paint(Color::Fixed(1), "100%-fix"),
+            "%{\u{1b}[38;5;1m%}100%%-fix%{\u{1b}[0m%}"
+        );
+        let v = View {
+            text: "100%-fix",
+            color: None,
+            shell: Shell::Zsh,
+        };
+        assert_eq!(v.to_string(), "100%%-fix");
+        assert_eq!(Shell::Bash.escape("100%-fix"), "100%-fix");
+    }
+
+    #[test]
+    fn tmux_hash() {
+        assert_eq!(
+            Shell::Tmux.paint(Color::Red, "fix-#123"),
+            "#[fg=red]fix-##123#[fg=default]"
+        );
+        let v = View {
+            text: "fix-#123",
+            color: None,
+            shell: Shell::Tmux,
+        };
+        assert_eq!(v.to_string(), "fix-##123");
+        assert_eq!(Shell::Zsh.escape("fix-#123"), "fix-#123");
+    }
+
+    #[test]
+    fn fish() {
+        assert_eq!(
+            Shell::Fish.paint(Color::Fixed(1), "foo"),
+            "\u{1b}[38;5;1mfoo\u{1b}[0m"
+        );
+    }
+
+    #[test]
+    fn tmux() {
+        assert_eq!(
+            Shell::Tmux.paint(Color::Fixed(1), "foo"),
+            "#[fg=colour1]foo#[fg=default]"
+        );
+        assert_eq!(
+            Shell::Tmux.paint(Color::Purple, "foo"),
+            "#[fg=magenta]foo#[fg=default]"
+        );
+        assert_eq!(
+            Shell::Tmux.paint(Color::RGB(255, 0, 16), "foo"),
+            "#[fg=#ff0010]foo#[fg=default]"
+        );
+    }
+
+    #[test]
+    fn none() {
+        assert_eq!(
+            Shell::None.paint(Color::Fixed(1), "foo"),
+            "\u{1b}[38;5;1mfoo\u{1b}[0m"
+        );
+    }
+
+    #[test]
+    fn all_views_are_escaped() {
+        let colors = Colors {
+            ok: Some(Color::Fixed(2)),
+            high: Some(Color::Fixed(1)),
+            normal: Some(Color::Fixed(3)),
+            shell: Shell::Zsh,
+        };
+        let mut p = Prompt::new(&RepoStatus {
+            branch: Some("master".into()),
+            detached: false,
+            upstream: None,
+            remote: None,
+            last_fetch: None,
+            state: git2::RepositoryState::Rebase,
+            progress: None,
+            rebase: None,
+        })
+        .with_branch(Some(BranchStatus {
+            upstream: Upstream::Diverged {
+                ahead: 1,
+                behind: 0,
+            },
+            ..Default::default()
+        }))
+        .with_local(Some(LocalStatus {
+            staged: 1,
+            unmerged: 1,
+            unstaged: 1,
+            untracked: 1,
+            stashed: 0,
+            ..Default::default()
+        }));
+        p.colors = colors;
+        let escaped = p
+            .to_string()
+            .replace("%{\u{1b}[38;5;1m%}", "")
+            .replace("%{\u{1b}[38;5;2m%}", "")
+            .replace("%{\u{1b}[38;5;3m%}", "")
+            .replace("%{\u{1b}[0m%}", "");
+        assert!(!escaped.contains('\u{1b}'), "{:?}", escaped);
+        assert_eq!(escaped, "rebase master ↑1 ✖1●1✚1… ");
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct StatusSymbols<'a> {
     pub nothing: &'a str,
@@ -188,16 +778,116 @@ pub struct StatusSymbols<'a> {
     pub unmerged: &'a str,
     pub unstaged: &'a str,
     pub untracked: &'a str,
+    pub stashed: &'a str,
+    // for the segments which did not finish in time
+    pub unknown: &'a str,
+    // the kinds of the changes in the detailed local status
+    pub new: &'a str,
+    pub modified: &'a str,
+    pub deleted: &'a str,
+    pub renamed: &'a str,
+    pub typechange: &'a str,
 }
 
 #[derive(Clone, Debug)]
 pub struct BranchSymbols<'a> {
     pub ahead: &'a str,
     pub behind: &'a str,
+    pub default_ahead: &'a str,
+    pub default_behind: &'a str,
+    // in front of the description of a detached HEAD
+    pub detached: &'a str,
+    // instead of the counts, when there are none
+    pub in_sync: &'a str,
+    pub no_upstream: &'a str,
+    pub gone: &'a str,
+    // the commits not pushed to where the branch is pushed, when it is not
+    // the upstream
+    pub push_ahead: &'a str,
+    // in front of the time since the last fetch
+    pub stale: &'a str,
+}
+
+/// A piece of the prompt which can be referenced from the layout.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Field {
+    State,
+    Branch,
+    // the branch the current branch tracks, when it is not the same
+    Upstream,
+    AheadBehind,
+    DefaultAheadBehind,
+    // the default branch the current branch is compared against
+    DefaultBranch,
+    // how long ago the remotes were fetched, when it is too long
+    Fetch,
+    Local,
+    // the local status with the staged and unstaged files by the kind of the
+    // change, e.g. '●A1M2✚M1'
+    LocalDetailed,
+    // the commit the branch is rebased onto
+    Onto,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Segment {
+    Text(String),
+    Field(Field),
+    // the inner segments are only rendered if the field is not empty
+    Cond(Field, Vec<Segment>),
+}
+
+/// The parsed layout of the prompt, see `parse::layout` for the format.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Layout(pub Vec<Segment>);
+
+impl Layout {
+    pub fn contains(&self, field: Field) -> bool {
+        self.first_of(&[field]).is_some()
+    }
+
+    /// The first of the fields which is printed by the layout.
+    fn first_of(&self, fields: &[Field]) -> Option<Field> {
+        fn find(segments: &[Segment], fields: &[Field]) -> Option<Field> {
+            segments.iter().find_map(|s| match s {
+                Segment::Text(_) => None,
+                Segment::Field(f) => Some(*f).filter(|f| fields.contains(f)),
+                Segment::Cond(f, inner) => Some(*f)
+                    .filter(|f| fields.contains(f))
+                    .or_else(|| find(inner, fields)),
+            })
+        }
+        find(&self.0, fields)
+    }
+}
+
+impl Default for Layout {
+    // {?state:{state} }{?branch:{branch} }{?upstream:{upstream} }
+    // {?ahead_behind:{ahead_behind} }
+    // {?default_ahead_behind:{default_ahead_behind} }{?fetch:{fetch} }
+    // {?local:{local} }
+    fn default() -> Layout {
+        Layout(
+            [
+                Field::State,
+                Field::Branch,
+                Field::Upstream,
+                Field::AheadBehind,
+                Field::DefaultAheadBehind,
+                Field::Fetch,
+                Field::Local,
+            ]
+            .iter()
+            .map(|f| Segment::Cond(*f, vec![Segment::Field(*f), Segment::Text(" ".to_owned())]))
+            .collect(),
+        )
+    }
 }
 
 pub struct RepoStateView<'a> {
     pub model: git2::RepositoryState,
+    // printed after the state, e.g. 'rebase-i 3/17'
+    pub progress: Option<Progress>,
     pub colors: &'a Colors,
 }
 
@@ -213,11 +903,22 @@ impl<'a> Display for RepoStateView<'a> {
             git2::RepositoryState::Rebase => "rebase",
             git2::RepositoryState::RebaseInteractive => "rebase-i",
             git2::RepositoryState::RebaseMerge => "rebase-m",
+            git2::RepositoryState::ApplyMailbox => "am",
+            git2::RepositoryState::ApplyMailboxOrRebase => "am/rebase",
             _ => "",
         };
+        if s.is_empty() {
+            return Ok(());
+        }
+        let progress = match &self.progress {
+            Some(Progress::Step(step, total)) => format!(" {}/{}", step, total),
+            Some(Progress::Left(left)) => format!(" {} left", left),
+            None => String::new(),
+        };
         let s = View {
-            text: s,
+            text: &format!("{}{}", s, progress),
             color: self.colors.high,
+            shell: self.colors.shell,
         };
         write!(f, "{}", s)
     }
@@ -228,38 +929,81 @@ impl<'a> Display for RepoStateView<'a> {
 mod repo_state_view {
     use super::*;
 
-    #[test]
-    fn empty() {
+    fn given(model: git2::RepositoryState, progress: Option<Progress>) -> String {
         let v = RepoStateView {
-            model: git2::RepositoryState::Clean,
+            model,
+            progress,
             colors: &NO_COLORS,
         };
-        assert_eq!(format!("{}", v), "");
+        format!("{}", v)
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(given(git2::RepositoryState::Clean, None), "");
     }
 
     #[test]
     fn rebase() {
-        let v = RepoStateView {
-            model: git2::RepositoryState::Rebase,
-            colors: &NO_COLORS,
-        };
-        assert_eq!(format!("{}", v), "rebase");
+        assert_eq!(given(git2::RepositoryState::Rebase, None), "rebase");
+    }
+
+    #[test]
+    fn progress() {
+        assert_eq!(
+            given(
+                git2::RepositoryState::RebaseInteractive,
+                Some(Progress::Step(3, 17))
+            ),
+            "rebase-i 3/17"
+        );
+        assert_eq!(
+            given(
+                git2::RepositoryState::CherryPickSequence,
+                Some(Progress::Left(2))
+            ),
+            "cherry-pick… 2 left"
+        );
+        assert_eq!(
+            given(
+                git2::RepositoryState::ApplyMailbox,
+                Some(Progress::Step(1, 4))
+            ),
+            "am 1/4"
+        );
     }
 }
 
 pub struct RepoStatusView<'a> {
     pub model: RepoStatus,
+    pub symbols: &'a BranchSymbols<'a>,
     pub colors: &'a Colors,
 }
 
 impl<'a> Display for RepoStatusView<'a> {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        let b = self.model.branch.as_ref().map(|b| View {
+        // the branch being rebased rather than the detached HEAD
+        let rebased = self
+            .model
+            .rebase
+            .as_ref()
+            .and_then(|r| r.head_name.as_ref());
+        let detached = View {
+            text: if self.model.detached && rebased.is_none() {
+                self.symbols.detached
+            } else {
+                ""
+            },
+            color: self.colors.high,
+            shell: self.colors.shell,
+        };
+        let b = rebased.or(self.model.branch.as_ref()).map(|b| View {
             text: b,
             color: self.colors.normal,
+            shell: self.colors.shell,
         });
         if let Some(b) = b {
-            write!(f, "{}", b)?;
+            write!(f, "{}{}", detached, b)?;
         }
         Ok(())
     }
@@ -270,28 +1014,220 @@ impl<'a> Display for RepoStatusView<'a> {
 mod repo_status_view {
     use super::*;
 
-    #[test]
-    fn nothing() {
+    fn given(branch: Option<&str>, detached: bool, rebase: Option<Rebase>) -> String {
         let v = RepoStatusView {
             model: RepoStatus {
-                branch: None,
+                branch: branch.map(str::to_owned),
+                detached,
+                upstream: None,
+                remote: None,
+                last_fetch: None,
                 state: git2::RepositoryState::Clean,
+                progress: None,
+                rebase,
+            },
+            symbols: &BranchSymbols {
+                ahead: "↑",
+                behind: "↓",
+                default_ahead: "⇡",
+                default_behind: "⇣",
+                detached: "➦",
+                in_sync: "=",
+                no_upstream: "",
+                gone: "⊘",
+                push_ahead: "⇈",
+                stale: "↻",
             },
             colors: &NO_COLORS,
         };
-        assert_eq!(format!("{}", v), "");
+        format!("{}", v)
+    }
+
+    #[test]
+    fn nothing() {
+        assert_eq!(given(None, false, None), "");
     }
 
     #[test]
     fn branch_is_shown() {
-        let v = RepoStatusView {
-            model: RepoStatus {
-                branch: Some("master".to_owned()),
-                state: git2::RepositoryState::Clean,
+        assert_eq!(given(Some("master"), false, None), "master");
+    }
+
+    #[test]
+    fn detached_is_marked() {
+        assert_eq!(given(Some("v1.2.0~3"), true, None), "➦v1.2.0~3");
+    }
+
+    #[test]
+    fn rebased_branch_is_shown() {
+        let rebase = Rebase {
+            head_name: Some("feature-x".to_owned()),
+            onto: Some("abc1234".to_owned()),
+        };
+        assert_eq!(given(Some("16fa43d"), true, Some(rebase)), "feature-x");
+    }
+}
+
+/// The upstream of the branch, abbreviated to the remote when it has the same
+/// name, and left out when that remote is origin.
+pub struct UpstreamView<'a> {
+    pub branch: Option<&'a str>,
+    pub upstream: Option<&'a str>,
+    // the remote of the upstream, from branch.<name>.remote
+    pub remote: Option<&'a str>,
+    pub colors: &'a Colors,
+}
+
+impl<'a> Display for UpstreamView<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let upstream = match self.upstream {
+            Some(u) => u,
+            None => return Ok(()),
+        };
+        // only the remote is left when the branch has the same name there
+        let remote = match (self.remote, self.branch) {
+            (Some(r), Some(b)) if upstream == format!("{}/{}", r, b) => Some(r),
+            _ => None,
+        };
+        let v = View {
+            text: match remote {
+                Some("origin") => "",
+                Some(remote) => remote,
+                None => upstream,
+            },
+            color: self.colors.normal,
+            shell: self.colors.shell,
+        };
+        write!(f, "{}", v)
+    }
+}
+
+#[cfg(test)]
+mod upstream_view {
+    use super::*;
+
+    fn given(branch: &str, remote: Option<&str>, upstream: Option<&str>) -> String {
+        let v = UpstreamView {
+            branch: Some(branch),
+            upstream,
+            remote,
+            colors: &NO_COLORS,
+        };
+        format!("{}", v)
+    }
+
+    #[test]
+    fn other_name() {
+        assert_eq!(
+            given("fix", Some("origin"), Some("origin/bugfix-1234")),
+            "origin/bugfix-1234"
+        );
+        // a branch in a directory which ends in the name of the local branch
+        assert_eq!(
+            given("fix", Some("origin"), Some("origin/a/fix")),
+            "origin/a/fix"
+        );
+    }
+
+    #[test]
+    fn same_name() {
+        assert_eq!(given("fix", Some("origin"), Some("origin/fix")), "");
+        assert_eq!(
+            given("fix", Some("upstream"), Some("upstream/fix")),
+            "upstream"
+        );
+        // a remote name with a slash in it
+        assert_eq!(
+            given("fix", Some("me/fork"), Some("me/fork/fix")),
+            "me/fork"
+        );
+    }
+
+    #[test]
+    fn local_branch() {
+        assert_eq!(given("fix", Some("."), Some("main")), "main");
+    }
+
+    #[test]
+    fn no_upstream() {
+        assert_eq!(given("fix", None, None), "");
+    }
+}
+
+/// The time since the last fetch, e.g. '↻3d', when it is over the threshold.
+pub struct FetchView<'a> {
+    pub model: Option<Duration>,
+    pub after: Option<Duration>,
+    pub symbols: &'a BranchSymbols<'a>,
+    pub colors: &'a Colors,
+}
+
+impl<'a> Display for FetchView<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let age = match (self.model, self.after) {
+            (Some(age), Some(after)) if age > after => age.as_secs(),
+            _ => return Ok(()),
+        };
+        let age = match age / 60 {
+            m if m < 60 => format!("{}m", m),
+            m if m < 60 * 24 => format!("{}h", m / 60),
+            m => format!("{}d", m / 60 / 24),
+        };
+        let symbol = View {
+            text: self.symbols.stale,
+            color: self.colors.high,
+            shell: self.colors.shell,
+        };
+        write!(f, "{}{}", symbol, age)
+    }
+}
+
+#[cfg(test)]
+mod fetch_view {
+    use super::*;
+
+    fn given(age: Option<u64>, after: Option<u64>) -> String {
+        let v = FetchView {
+            model: age.map(Duration::from_secs),
+            after: after.map(Duration::from_secs),
+            symbols: &BranchSymbols {
+                ahead: "↑",
+                behind: "↓",
+                default_ahead: "⇡",
+                default_behind: "⇣",
+                detached: "➦",
+                in_sync: "=",
+                no_upstream: "",
+                gone: "⊘",
+                push_ahead: "⇈",
+                stale: "↻",
             },
             colors: &NO_COLORS,
         };
-        assert_eq!(format!("{}", v), "master");
+        format!("{}", v)
+    }
+
+    #[test]
+    fn fresh() {
+        assert_eq!(given(Some(60), Some(3600)), "");
+        assert_eq!(given(Some(7200), None), "");
+        assert_eq!(given(None, Some(3600)), "");
+    }
+
+    #[test]
+    fn stale() {
+        assert_eq!(given(Some(3000), Some(60)), "↻50m");
+        assert_eq!(given(Some(5 * 3600 + 10), Some(3600)), "↻5h");
+        assert_eq!(given(Some(3 * 86400 + 7200), Some(3600)), "↻3d");
+    }
+}
+
+// the walk of the commits stops as soon as one of the counts is over the
+// limit, so the other one might be missing some of them
+fn partial(other: usize, limit: Option<usize>) -> bool {
+    match limit {
+        Some(limit) => other > limit,
+        None => false,
     }
 }
 
@@ -306,20 +1242,46 @@ impl<'a> Display for BranchStatusView<'a> {
         self.model
             .as_ref()
             .map(|b| {
-                if b.ahead == 0 && b.behind == 0 {
-                    return Ok(());
-                }
-                let ahead = StatView {
-                    symbol: self.symbols.ahead,
-                    n: b.ahead,
+                let push = StatView {
+                    symbol: self.symbols.push_ahead,
+                    n: b.push_ahead.unwrap_or(0),
+                    limit: b.limit,
+                    partial: false,
                     color: self.colors.normal,
+                    shell: self.colors.shell,
                 };
-                let behind = StatView {
-                    symbol: self.symbols.behind,
-                    n: b.behind,
-                    color: self.colors.normal,
+                let (symbol, color) = match b.upstream {
+                    Upstream::None => (self.symbols.no_upstream, self.colors.normal),
+                    Upstream::Gone => (self.symbols.gone, self.colors.high),
+                    Upstream::InSync => (self.symbols.in_sync, self.colors.ok),
+                    Upstream::Diverged { ahead, behind } => {
+                        let (ahead_partial, behind_partial) =
+                            (partial(behind, b.limit), partial(ahead, b.limit));
+                        let ahead = StatView {
+                            symbol: self.symbols.ahead,
+                            n: ahead,
+                            limit: b.limit,
+                            partial: ahead_partial,
+                            color: self.colors.normal,
+                            shell: self.colors.shell,
+                        };
+                        let behind = StatView {
+                            symbol: self.symbols.behind,
+                            n: behind,
+                            limit: b.limit,
+                            partial: behind_partial,
+                            color: self.colors.normal,
+                            shell: self.colors.shell,
+                        };
+                        return write!(f, "{}{}{}", ahead, behind, push);
+                    }
                 };
-                write!(f, "{}{}", ahead, behind)
+                let v = View {
+                    text: symbol,
+                    color,
+                    shell: self.colors.shell,
+                };
+                write!(f, "{}{}", v, push)
             })
             .unwrap_or(Ok(()))
     }
@@ -336,6 +1298,14 @@ mod branch_status_view {
             symbols: &BranchSymbols {
                 ahead: "↑",
                 behind: "↓",
+                default_ahead: "⇡",
+                default_behind: "⇣",
+                detached: "➦",
+                in_sync: "=",
+                no_upstream: "",
+                gone: "⊘",
+                push_ahead: "⇈",
+                stale: "↻",
             },
             colors: &super::NO_COLORS,
         };
@@ -343,13 +1313,55 @@ mod branch_status_view {
     }
 
     fn given_some(ahead: usize, behind: usize) -> String {
-        given(Some(BranchStatus { ahead, behind }))
+        given(Some(BranchStatus {
+            upstream: (ahead, behind).into(),
+            ..Default::default()
+        }))
+    }
+
+    fn given_upstream(upstream: Upstream) -> String {
+        given(Some(BranchStatus {
+            upstream,
+            ..Default::default()
+        }))
     }
 
     #[test]
     fn is_empty() {
         assert_eq!(given(None), "");
-        assert_eq!(given_some(0, 0), "");
+    }
+
+    #[test]
+    fn no_upstream() {
+        assert_eq!(given_upstream(Upstream::None), "");
+    }
+
+    #[test]
+    fn gone() {
+        assert_eq!(given_upstream(Upstream::Gone), "⊘");
+    }
+
+    #[test]
+    fn in_sync() {
+        assert_eq!(given_some(0, 0), "=");
+    }
+
+    #[test]
+    fn ahead_of_push_target() {
+        let v = given(Some(BranchStatus {
+            upstream: Upstream::Diverged {
+                ahead: 0,
+                behind: 4,
+            },
+            push_ahead: Some(2),
+            ..Default::default()
+        }));
+        assert_eq!(v, "↓4⇈2");
+        let v = given(Some(BranchStatus {
+            push_ahead: Some(1),
+            ..Default::default()
+        }));
+        assert_eq!(v, "⇈1");
     }
 
     #[test]
@@ -361,6 +1373,126 @@ mod branch_status_view {
     fn behind() {
         assert_eq!(given_some(1, 3), "↑1↓3");
     }
+
+    #[test]
+    fn over_the_limit() {
+        let v = given(Some(BranchStatus {
+            upstream: Upstream::Diverged {
+                ahead: 1000,
+                behind: 3,
+            },
+            limit: Some(999),
+            ..Default::default()
+        }));
+        assert_eq!(v, "↑999+↓3+");
+    }
+
+    #[test]
+    fn other_count_is_partial() {
+        let given_limit = |ahead, behind| {
+            given(Some(BranchStatus {
+                upstream: Upstream::Diverged { ahead, behind },
+                limit: Some(2),
+                ..Default::default()
+            }))
+        };
+        assert_eq!(given_limit(0, 3), "↑?↓2+");
+        assert_eq!(given_limit(1, 3), "↑1+↓2+");
+        assert_eq!(given_limit(3, 3), "↑2+↓2+");
+        assert_eq!(given_limit(2, 1), "↑2↓1");
+    }
+}
+
+pub struct DefaultBranchStatusView<'a> {
+    pub model: Option<BranchStatus>,
+    pub symbols: &'a BranchSymbols<'a>,
+    pub colors: &'a Colors,
+}
+
+impl<'a> Display for DefaultBranchStatusView<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.model
+            .as_ref()
+            .map(|b| {
+                let ahead = StatView {
+                    symbol: self.symbols.default_ahead,
+                    n: b.default_ahead,
+                    limit: b.limit,
+                    partial: partial(b.default_behind, b.limit),
+                    color: self.colors.normal,
+                    shell: self.colors.shell,
+                };
+                let behind = StatView {
+                    symbol: self.symbols.default_behind,
+                    n: b.default_behind,
+                    limit: b.limit,
+                    partial: partial(b.default_ahead, b.limit),
+                    color: self.colors.normal,
+                    shell: self.colors.shell,
+                };
+                write!(f, "{}{}", ahead, behind)
+            })
+            .unwrap_or(Ok(()))
+    }
+}
+
+#[cfg(test)]
+#[allow(dead_code)]
+mod default_branch_status_view {
+    use super::*;
+
+    fn given(m: Option<BranchStatus>) -> String {
+        let v = DefaultBranchStatusView {
+            model: m,
+            symbols: &BranchSymbols {
+                ahead: "↑",
+                behind: "↓",
+                default_ahead: "⇡",
+                default_behind: "⇣",
+                detached: "➦",
+                in_sync: "=",
+                no_upstream: "",
+                gone: "⊘",
+                push_ahead: "⇈",
+                stale: "↻",
+            },
+            colors: &super::NO_COLORS,
+        };
+        format!("{}", v)
+    }
+
+    #[test]
+    fn is_empty() {
+        assert_eq!(given(None), "");
+        assert_eq!(
+            given(Some(BranchStatus {
+                upstream: Upstream::Diverged {
+                    ahead: 1,
+                    behind: 2,
+                },
+                ..Default::default()
+            })),
+            ""
+        );
+    }
+
+    #[test]
+    fn only_default_counters() {
+        assert_eq!(
+            given(Some(BranchStatus {
+                upstream: Upstream::Diverged {
+                    ahead: 1,
+                    behind: 2,
+                },
+                push_ahead: None,
+                default_ahead: 3,
+                default_behind: 4,
+                default_branch: Some(DefaultBranch::RemoteHead("main".into())),
+                limit: None,
+            })),
+            "⇡3⇣4"
+        );
+    }
 }
 
 const LOCAL_CLEAN: LocalStatus = LocalStatus {
@@ -368,38 +1500,97 @@ const LOCAL_CLEAN: LocalStatus = LocalStatus {
     unmerged: 0,
     unstaged: 0,
     untracked: 0,
+    stashed: 0,
+    index: Changes::NONE,
+    worktree: Changes::NONE,
 };
 
 pub struct LocalStatusView<'a> {
     pub model: LocalStatus,
+    // the staged and the unstaged files are counted by the kind of the change
+    pub detailed: bool,
+    pub untracked: Untracked,
     pub symbols: &'a StatusSymbols<'a>,
     pub colors: &'a Colors,
 }
 
 impl<'a> Display for LocalStatusView<'a> {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        if LOCAL_CLEAN == self.model {
+        // stashes do not make the working tree dirty
+        let stashed = StatView {
+            symbol: self.symbols.stashed,
+            n: self.model.stashed,
+            limit: None,
+            partial: false,
+            color: self.colors.normal,
+            shell: self.colors.shell,
+        };
+        // the untracked files are unknown rather than none
+        let not_counted = format!("{}{}", self.symbols.untracked, self.symbols.unknown);
+        let not_counted = View {
+            text: if self.untracked == Untracked::None {
+                &not_counted
+            } else {
+                ""
+            },
+            color: None,
+            shell: self.colors.shell,
+        };
+        if LOCAL_CLEAN
+            == (LocalStatus {
+                stashed: 0,
+                ..self.model.clone()
+            })
+        {
             let v = View {
                 text: self.symbols.nothing,
                 color: self.colors.ok,
+                shell: self.colors.shell,
             };
-            write!(f, "{}", v)
+            write!(f, "{}{}{}", v, not_counted, stashed)
         } else {
             let unmerged = StatView {
                 symbol: self.symbols.unmerged,
                 n: self.model.unmerged,
+                limit: None,
+                partial: false,
                 color: self.colors.high,
+                shell: self.colors.shell,
             };
-            let unstaged = StatView {
-                symbol: self.symbols.unstaged,
-                n: self.model.unstaged,
-                color: self.colors.normal,
-            };
-            let staged = StatView {
-                symbol: self.symbols.staged,
-                n: self.model.staged,
-                color: self.colors.ok,
+            let changes = |symbol, n, changes, color| -> String {
+                if self.detailed {
+                    ChangesView {
+                        symbol,
+                        model: changes,
+                        symbols: self.symbols,
+                        color,
+                        shell: self.colors.shell,
+                    }
+                    .to_string()
+                } else {
+                    StatView {
+                        symbol,
+                        n,
+                        limit: None,
+                        partial: false,
+                        color,
+                        shell: self.colors.shell,
+                    }
+                    .to_string()
+                }
             };
+            let unstaged = changes(
+                self.symbols.unstaged,
+                self.model.unstaged,
+                self.model.worktree,
+                self.colors.normal,
+            );
+            let staged = changes(
+                self.symbols.staged,
+                self.model.staged,
+                self.model.index,
+                self.colors.ok,
+            );
             let untracked = View {
                 text: if self.model.untracked == 0 {
                     ""
@@ -407,26 +1598,95 @@ impl<'a> Display for LocalStatusView<'a> {
                     self.symbols.untracked
                 },
                 color: None,
+                shell: self.colors.shell,
             };
-            write!(f, "{}{}{}{}", unmerged, staged, unstaged, untracked)
+            write!(
+                f,
+                "{}{}{}{}{}{}",
+                unmerged, staged, unstaged, untracked, not_counted, stashed
+            )
         }
     }
 }
 
+/// The symbol of the staged or the unstaged files followed by the counts of the
+/// kinds of the changes, e.g. '●A1M2'.
+pub struct ChangesView<'a> {
+    pub symbol: &'a str,
+    pub model: Changes,
+    pub symbols: &'a StatusSymbols<'a>,
+    pub color: Option<Color>,
+    pub shell: Shell,
+}
+
+impl<'a> Display for ChangesView<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.model == Changes::NONE {
+            return Ok(());
+        }
+        let symbol = View {
+            text: self.symbol,
+            color: self.color,
+            shell: self.shell,
+        };
+        write!(f, "{}", symbol)?;
+        let m = &self.model;
+        for (symbol, n) in [
+            (self.symbols.new, m.new),
+            (self.symbols.modified, m.modified),
+            (self.symbols.deleted, m.deleted),
+            (self.symbols.renamed, m.renamed),
+            (self.symbols.typechange, m.typechange),
+        ] {
+            let v = StatView {
+                symbol,
+                n,
+                limit: None,
+                partial: false,
+                color: self.color,
+                shell: self.shell,
+            };
+            write!(f, "{}", v)?;
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 #[allow(dead_code)]
 mod local_status_view {
     use super::*;
 
     fn given(m: LocalStatus) -> String {
+        given_untracked(m, Untracked::Normal)
+    }
+
+    fn given_untracked(m: LocalStatus, untracked: Untracked) -> String {
+        render(m, untracked, false)
+    }
+
+    fn given_detailed(m: LocalStatus) -> String {
+        render(m, Untracked::Normal, true)
+    }
+
+    fn render(m: LocalStatus, untracked: Untracked, detailed: bool) -> String {
         let v = LocalStatusView {
             model: m,
+            detailed,
+            untracked,
             symbols: &StatusSymbols {
                 nothing: "✔",
                 staged: ".",
                 unmerged: "x",
                 unstaged: "+",
                 untracked: "…",
+                stashed: "⚑",
+                unknown: "?",
+                new: "A",
+                modified: "M",
+                deleted: "D",
+                renamed: "R",
+                typechange: "T",
             },
             colors: &NO_COLORS,
         };
@@ -440,6 +1700,8 @@ mod local_status_view {
             unmerged: 0,
             unstaged: 0,
             untracked: 0,
+            stashed: 0,
+            ..Default::default()
         });
         assert_eq!(v, "✔");
     }
@@ -451,6 +1713,8 @@ mod local_status_view {
             unmerged: 0,
             unstaged: 0,
             untracked: 4,
+            stashed: 0,
+            ..Default::default()
         });
         assert_eq!(v, ".1…");
     }
@@ -462,14 +1726,72 @@ mod local_status_view {
             unmerged: 2,
             unstaged: 3,
             untracked: 4,
+            stashed: 0,
+            ..Default::default()
         });
         assert_eq!(v, "x2.1+3…");
     }
+
+    #[test]
+    fn stashed() {
+        let v = given(LocalStatus {
+            stashed: 2,
+            ..LOCAL_CLEAN
+        });
+        assert_eq!(v, "✔⚑2");
+
+        let v = given(LocalStatus {
+            unstaged: 1,
+            stashed: 2,
+            ..LOCAL_CLEAN
+        });
+        assert_eq!(v, "+1⚑2");
+    }
+
+    #[test]
+    fn untracked_not_counted() {
+        assert_eq!(given_untracked(LOCAL_CLEAN, Untracked::None), "✔…?");
+        let v = given_untracked(
+            LocalStatus {
+                unstaged: 1,
+                stashed: 2,
+                ..LOCAL_CLEAN
+            },
+            Untracked::None,
+        );
+        assert_eq!(v, "+1…?⚑2");
+    }
+
+    #[test]
+    fn detailed() {
+        let v = given_detailed(LocalStatus {
+            staged: 3,
+            unstaged: 1,
+            untracked: 2,
+            index: Changes {
+                new: 1,
+                modified: 2,
+                ..Changes::NONE
+            },
+            worktree: Changes {
+                deleted: 1,
+                ..Changes::NONE
+            },
+            ..LOCAL_CLEAN
+        });
+        assert_eq!(v, ".A1M2+D1…");
+    }
+
+    #[test]
+    fn detailed_clean() {
+        assert_eq!(given_detailed(LOCAL_CLEAN), "✔");
+    }
 }
 
 pub struct View<'a> {
     pub text: &'a str,
     pub color: Option<Color>,
+    pub shell: Shell,
 }
 
 impl<'a> Display for View<'a> {
@@ -477,8 +1799,8 @@ impl<'a> Display for View<'a> {
         match self.text {
             "" => Ok(()),
             t => match self.color {
-                Some(c) => write!(f, "{}", c.paint(t)),
-                None => write!(f, "{}", t),
+                Some(c) => write!(f, "{}", self.shell.paint(c, t)),
+                None => write!(f, "{}", self.shell.escape(t)),
             },
         }
     }
@@ -487,11 +1809,12 @@ impl<'a> Display for View<'a> {
 #[cfg(test)]
 #[allow(dead_code)]
 mod simple_view_tests {
-    use super::View;
+    use super::{Shell, View};
     use ansi_term::Color;
 
     fn given(text: &str, color: Option<Color>) -> String {
-        format!("{}", View { text, color })
+        let shell = Shell::None;
+        format!("{}", View { text, color, shell })
     }
 
     #[test]
@@ -522,17 +1845,26 @@ mod simple_view_tests {
 pub struct StatView<'a> {
     pub symbol: &'a str,
     pub n: usize,
+    // the numbers above it are not exact
+    pub limit: Option<usize>,
+    // the number is only the ones found before the walk stopped at the limit
+    pub partial: bool,
     pub color: Option<Color>,
+    pub shell: Shell,
 }
 
 impl<'a> Display for StatView<'a> {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        match self.n {
-            0 => Ok(()),
-            n => match self.color {
-                Some(c) => write!(f, "{}{}", c.paint(self.symbol), n),
-                None => write!(f, "{}{}", self.symbol, n),
-            },
+        let n = match (self.n, self.limit) {
+            (n, Some(limit)) if n > limit => format!("{}+", limit),
+            (0, _) if self.partial => "?".to_owned(),
+            (0, _) => return Ok(()),
+            (n, _) if self.partial => format!("{}+", n),
+            (n, _) => n.to_string(),
+        };
+        match self.color {
+            Some(c) => write!(f, "{}{}", self.shell.paint(c, self.symbol), n),
+            None => write!(f, "{}{}", self.shell.escape(self.symbol), n),
         }
     }
 }
@@ -540,11 +1872,26 @@ impl<'a> Display for StatView<'a> {
 #[cfg(test)]
 #[allow(dead_code)]
 mod stat_view_tests {
-    use super::StatView;
+    use super::{Shell, StatView};
     use ansi_term::Color;
 
     fn given(symbol: &str, n: usize, color: Option<Color>) -> String {
-        format!("{}", StatView { symbol, n, color })
+        given_limit(symbol, n, None, color)
+    }
+
+    fn given_limit(symbol: &str, n: usize, limit: Option<usize>, color: Option<Color>) -> String {
+        let shell = Shell::None;
+        format!(
+            "{}",
+            StatView {
+                symbol,
+                n,
+                limit,
+                partial: false,
+                color,
+                shell
+            }
+        )
     }
 
     #[test]
@@ -567,6 +1914,16 @@ mod stat_view_tests {
         assert_eq!(given("foo", 3, None), "foo3");
     }
 
+    #[test]
+    fn limit() {
+        assert_eq!(given_limit("foo", 999, Some(999), None), "foo999");
+        assert_eq!(given_limit("foo", 1000, Some(999), None), "foo999+");
+        assert_eq!(
+            given_limit("foo", 1000, Some(999), Some(Color::Fixed(1))),
+            format!("{}999+", Color::Fixed(1).paint("foo"))
+        );
+    }
+
     #[test]
     fn color() {
         let colors = vec![1, 2, 3];